@@ -1,7 +1,7 @@
-// Build the VM's protobuf into a Rust server
+// Build the VM's protobuf into a Rust server (and the client the host side uses)
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     tonic_build::configure()
-        .build_client(false)
+        .build_client(true)
         .format(true)
         .compile(
             &[
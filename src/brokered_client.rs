@@ -0,0 +1,62 @@
+// Typed clients for the host's brokered servers, so callbacks to the host don't need to build
+// their own from a Channel. Tonic's generated clients don't share a trait to build them by, so
+// they're given one with the brokered_client macro, in the crate they're generated in:
+//
+//     grr_plugin::brokered_client!(callback::callback_client::CallbackClient);
+//     let client = broker.dial_client::<CallbackClient<BrokeredChannel>>(service_id).await?;
+use std::sync::Arc;
+use tonic::service::interceptor::InterceptedService;
+use tonic::service::Interceptor;
+use tonic::transport::Channel;
+use tonic::{Request, Status};
+use tonic_health::proto::health_client::HealthClient;
+
+// A connection to one of the host's brokered servers, which runs the broker's interceptors on
+// every call.
+pub type BrokeredChannel = InterceptedService<Channel, Interceptors>;
+
+type InterceptorFn = dyn Fn(Request<()>) -> Result<Request<()>, Status> + Send + Sync;
+
+// Run in the order they were added, each on the request the one before returned. The first to
+// return an error fails the call with it.
+#[derive(Clone, Default)]
+pub struct Interceptors(Arc<Vec<Arc<InterceptorFn>>>);
+
+impl Interceptors {
+    pub(crate) fn with<F>(&self, interceptor: F) -> Self
+    where
+        F: Fn(Request<()>) -> Result<Request<()>, Status> + Send + Sync + 'static,
+    {
+        let mut interceptors = self.0.as_ref().clone();
+        interceptors.push(Arc::new(interceptor));
+        Self(Arc::new(interceptors))
+    }
+}
+
+impl Interceptor for Interceptors {
+    // Status is what tonic's interceptors fail with, however big it is.
+    #[allow(clippy::result_large_err)]
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        self.0
+            .iter()
+            .try_fold(request, |request, interceptor| interceptor(request))
+    }
+}
+
+pub trait BrokeredClient {
+    fn from_channel(channel: BrokeredChannel) -> Self;
+}
+
+// Lets a tonic-generated client be dialed with GRpcBroker::dial_client, given its path.
+#[macro_export]
+macro_rules! brokered_client {
+    ($($client:ident)::+) => {
+        impl $crate::BrokeredClient for $($client)::+<$crate::BrokeredChannel> {
+            fn from_channel(channel: $crate::BrokeredChannel) -> Self {
+                Self::new(channel)
+            }
+        }
+    };
+}
+
+brokered_client!(HealthClient);
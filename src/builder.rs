@@ -0,0 +1,221 @@
+// Collects how a Server should be set up, and checks it all before anything is served. Options
+// left unset are taken from the host, through the environment, as go-plugin's are.
+use super::error::Error;
+use super::grpc_mux::{self, GRpcServerMuxer};
+use super::grpc_stdio::StdioSource;
+use super::tls::AutoMtls;
+use super::transport::ListenConfig;
+use super::unix::SocketConfig;
+use super::{HandshakeConfig, ServeMode, Server, Transport};
+use std::path::PathBuf;
+use std::time::Duration;
+
+pub struct ServerBuilder {
+    protocol_version: u32,
+    handshake_config: HandshakeConfig,
+    transport: Transport,
+    socket_dir: Option<PathBuf>,
+    socket_group: Option<String>,
+    auto_mtls: bool,
+    multiplex_grpc: bool,
+    serving: bool,
+    capture_stdio: bool,
+    grace_period: Option<Duration>,
+    shutdown_on_stdin_close: bool,
+    handle_signals: bool,
+    debug: bool,
+}
+
+impl ServerBuilder {
+    pub fn new(protocol_version: u32, handshake_config: HandshakeConfig) -> Self {
+        Self {
+            protocol_version,
+            handshake_config,
+            transport: Transport::default(),
+            socket_dir: None,
+            socket_group: None,
+            auto_mtls: true,
+            multiplex_grpc: true,
+            serving: true,
+            capture_stdio: true,
+            grace_period: None,
+            shutdown_on_stdin_close: false,
+            handle_signals: true,
+            debug: false,
+        }
+    }
+
+    // Serve the plugin, and servers brokered through GRpcBroker, over this transport.
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    // Where to create unix sockets, instead of PLUGIN_UNIX_SOCKET_DIR or the temp directory.
+    pub fn socket_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.socket_dir = Some(dir.into());
+        self
+    }
+
+    // The group, by name or gid, that can connect to our unix sockets, instead of PLUGIN_UNIX_SOCKET_GROUP.
+    pub fn socket_group(mut self, group: &str) -> Self {
+        self.socket_group = Some(group.to_string());
+        self
+    }
+
+    // Whether to use TLS when the host asks for AutoMTLS. On by default.
+    pub fn auto_mtls(mut self, auto_mtls: bool) -> Self {
+        self.auto_mtls = auto_mtls;
+        self
+    }
+
+    // Whether to multiplex brokered connections when the host asks to. On by default.
+    pub fn multiplex_grpc(mut self, multiplex_grpc: bool) -> Self {
+        self.multiplex_grpc = multiplex_grpc;
+        self
+    }
+
+    // Whether the health service reports the plugin as serving once it starts. On by default.
+    pub fn serving(mut self, serving: bool) -> Self {
+        self.serving = serving;
+        self
+    }
+
+    // Whether to capture stdout and stderr, and stream them to the host. On by default, and
+    // when off, the host's stdio stream is empty.
+    pub fn capture_stdio(mut self, capture_stdio: bool) -> Self {
+        self.capture_stdio = capture_stdio;
+        self
+    }
+
+    // How long in-flight calls get to finish once the host shuts us down. By default, they all do.
+    pub fn grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = Some(grace_period);
+        self
+    }
+
+    // Whether to shut down when the host closes our stdin. Off by default, since go-plugin hosts
+    // hand us their own stdin, which may be closed from the start.
+    pub fn shutdown_on_stdin_close(mut self, shutdown_on_stdin_close: bool) -> Self {
+        self.shutdown_on_stdin_close = shutdown_on_stdin_close;
+        self
+    }
+
+    // Whether to ignore SIGINT, which the host gets too when Ctrl-C is pressed in its terminal,
+    // and shut down gracefully on SIGTERM, as go-plugin does. On by default. Turn it off to
+    // handle signals yourself.
+    pub fn handle_signals(mut self, handle_signals: bool) -> Self {
+        self.handle_signals = handle_signals;
+        self
+    }
+
+    // Serve without a host, e.g. under a debugger. The magic cookie isn't checked, and a ReattachConfig
+    // is printed instead of the handshake, for a host to attach with later. The plugin serves until
+    // the host shuts it down, or it gets SIGINT or SIGTERM.
+    pub fn debug(mut self, debug: bool) -> Self {
+        self.debug = debug;
+        self
+    }
+
+    pub fn build(self) -> Result<Server, Error> {
+        if !self.debug && self.handshake_config.magic_cookie_key.is_empty() {
+            return Err(Error::InvalidConfig(
+                "the magic cookie key is empty".to_string(),
+            ));
+        }
+        if self.transport == Transport::Tcp
+            && (self.socket_dir.is_some() || self.socket_group.is_some())
+        {
+            return Err(Error::InvalidConfig(
+                "unix socket options were given for the tcp transport".to_string(),
+            ));
+        }
+
+        let mut unix = SocketConfig::from_env()?;
+        if let Some(dir) = self.socket_dir {
+            unix = unix.with_dir(dir);
+        }
+        if let Some(group) = &self.socket_group {
+            unix = unix.with_group(group)?;
+        }
+        if let Some(dir) = unix.dir() {
+            if !dir.is_dir() {
+                return Err(Error::InvalidConfig(format!(
+                    "the unix socket directory {:?} is not a directory",
+                    dir
+                )));
+            }
+        }
+
+        let mut server = Server::without_env(self.protocol_version, self.handshake_config)?;
+        server.listen_config = ListenConfig {
+            transport: self.transport,
+            unix,
+        };
+
+        // Hosts with AutoMTLS enabled hand us their certificate to trust.
+        if self.auto_mtls {
+            server.auto_mtls = AutoMtls::from_env()?;
+        }
+
+        // Hosts that support it ask for brokered connections to share their connection to us.
+        if self.multiplex_grpc && grpc_mux::multiplex_from_env() {
+            server.muxer = Some(GRpcServerMuxer::new());
+        }
+
+        server.serving = self.serving;
+        if !self.capture_stdio {
+            server.stdio = StdioSource::Inherit;
+        }
+        server.drain = server.drain.with_grace_period(self.grace_period);
+        server.watch_stdin = self.shutdown_on_stdin_close;
+        server.handle_signals = self.handle_signals;
+        if self.debug {
+            server.mode = ServeMode::Debug;
+        }
+
+        Ok(server)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert_matches::assert_matches;
+
+    #[test]
+    fn test_build_validates() {
+        let handshake_config = || HandshakeConfig {
+            magic_cookie_key: "foo".to_string(),
+            magic_cookie_value: "bar".to_string(),
+        };
+
+        assert_matches!(
+            ServerBuilder::new(1, handshake_config())
+                .transport(Transport::Tcp)
+                .socket_dir("/tmp")
+                .build()
+                .err(),
+            Some(Error::InvalidConfig(_))
+        );
+        assert_matches!(
+            ServerBuilder::new(1, handshake_config())
+                .socket_dir("/no/such/dir/for/grr-plugin")
+                .build()
+                .err(),
+            Some(Error::InvalidConfig(_))
+        );
+        assert_matches!(
+            ServerBuilder::new(1, handshake_config())
+                .socket_group("no-such-group-for-grr-plugin")
+                .build()
+                .err(),
+            Some(Error::UnixSocketGroup(_))
+        );
+        assert!(ServerBuilder::new(1, handshake_config())
+            .socket_dir("/tmp")
+            .grace_period(Duration::from_secs(1))
+            .build()
+            .is_ok());
+    }
+}
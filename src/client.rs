@@ -0,0 +1,285 @@
+// The host side of go-plugin: launch a plugin binary, handshake with it, and hand back a gRPC Channel to it.
+// Modeled after: https://github.com/hashicorp/go-plugin/blob/master/client.go
+use super::error::Error;
+use super::grpc_broker::dial;
+use super::grpc_controller::grpc_plugins::grpc_controller_client::GrpcControllerClient;
+use super::grpc_controller::grpc_plugins::Empty;
+use super::handshake::{Handshake, ENV_PLUGIN_PROTOCOL_VERSIONS, GRPC_CORE_PROTOCOL_VERSION};
+use super::{ConnInfo, HandshakeConfig};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::{Child, ChildStdin, Command};
+use tokio::time::timeout;
+use tonic::transport::Channel;
+
+// How long to wait for the plugin to print its handshake line.
+// Same as go-plugin's default StartTimeout.
+const START_TIMEOUT: Duration = Duration::from_secs(60);
+
+// How long to wait for the plugin to exit after asking it to Shutdown, before killing it.
+const KILL_GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+pub struct Client {
+    child: Child,
+    // Held open while we're running, for plugins that shut down when it closes.
+    stdin: Option<ChildStdin>,
+    handshake: Handshake,
+    channel: Channel,
+}
+
+impl Client {
+    // Spawns the plugin command with the magic cookie set, waits for its handshake line,
+    // and connects to the address it advertised.
+    pub async fn start(
+        cmd: Command,
+        protocol_version: u32,
+        handshake_config: &HandshakeConfig,
+    ) -> Result<Client, Error> {
+        Self::start_within(cmd, protocol_version, handshake_config, START_TIMEOUT).await
+    }
+
+    async fn start_within(
+        mut cmd: Command,
+        protocol_version: u32,
+        handshake_config: &HandshakeConfig,
+        start_timeout: Duration,
+    ) -> Result<Client, Error> {
+        cmd.env(
+            &handshake_config.magic_cookie_key,
+            &handshake_config.magic_cookie_value,
+        )
+        .env(ENV_PLUGIN_PROTOCOL_VERSIONS, protocol_version.to_string())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true);
+
+        log::info!("Starting plugin: {:?}", cmd);
+        let mut child = cmd.spawn()?;
+        log::trace!("Plugin started with pid {:?}", child.id());
+        let stdin = child.stdin.take();
+
+        let stdout = child.stdout.take().ok_or_else(|| {
+            Error::InvalidHandshake(
+                "the plugin's stdout was not piped, so the handshake can't be read from it"
+                    .to_string(),
+            )
+        })?;
+        let mut lines = BufReader::new(stdout).lines();
+
+        // The child is killed on drop, so returning early on any error here cleans it up.
+        let line = match timeout(start_timeout, lines.next_line()).await {
+            Err(_) => return Err(Error::HandshakeTimeout(start_timeout)),
+            Ok(Err(e)) => return Err(Error::Io(e)),
+            Ok(Ok(None)) => {
+                return Err(Error::InvalidHandshake(
+                    "plugin exited or closed stdout before printing a handshake".to_string(),
+                ))
+            }
+            Ok(Ok(Some(line))) => line,
+        };
+        log::info!("Received handshake from plugin: {}", line);
+
+        let handshake: Handshake = line.parse()?;
+        if handshake.core_protocol_version != GRPC_CORE_PROTOCOL_VERSION {
+            return Err(Error::InvalidHandshake(format!(
+                "incompatible core protocol version {}, expected {}",
+                handshake.core_protocol_version, GRPC_CORE_PROTOCOL_VERSION
+            )));
+        }
+        if handshake.app_protocol_version != protocol_version {
+            return Err(Error::InvalidHandshake(format!(
+                "incompatible app protocol version {}, expected {}",
+                handshake.app_protocol_version, protocol_version
+            )));
+        }
+        if handshake.protocol != "grpc" {
+            return Err(Error::InvalidHandshake(format!(
+                "unsupported protocol {}, only grpc is supported",
+                handshake.protocol
+            )));
+        }
+        // We only dial the plugin in plaintext, over its own connection.
+        if handshake.server_cert.is_some() {
+            return Err(Error::InvalidHandshake(
+                "the plugin serves AutoMTLS, which isn't supported".to_string(),
+            ));
+        }
+        if handshake.multiplex_grpc {
+            return Err(Error::InvalidHandshake(
+                "the plugin multiplexes brokered connections, which isn't supported".to_string(),
+            ));
+        }
+
+        // Anything else the plugin prints goes to the log, same as go-plugin does.
+        tokio::spawn(async move {
+            while let Ok(Some(line)) = lines.next_line().await {
+                log::debug!("plugin stdout: {}", line);
+            }
+            log::trace!("plugin stdout closed");
+        });
+
+        let channel = dial(
+            ConnInfo {
+                service_id: 0,
+                network: handshake.network.clone(),
+                address: handshake.address.clone(),
+                knock: None,
+            },
+            None,
+        )
+        .await?;
+        log::info!(
+            "Connected to plugin at {}:{}",
+            handshake.network,
+            handshake.address
+        );
+
+        Ok(Client {
+            child,
+            stdin,
+            handshake,
+            channel,
+        })
+    }
+
+    pub fn channel(&self) -> Channel {
+        self.channel.clone()
+    }
+
+    pub fn handshake(&self) -> &Handshake {
+        &self.handshake
+    }
+
+    pub fn id(&self) -> Option<u32> {
+        self.child.id()
+    }
+
+    // Asks the plugin to shut down through the GRPCController, and kills it
+    // if it hasn't exited within a short grace period.
+    pub async fn kill(mut self) -> Result<(), Error> {
+        log::info!("Asking plugin to shut down...");
+        let mut controller = GrpcControllerClient::new(self.channel.clone());
+        if let Err(status) = controller.shutdown(Empty {}).await {
+            log::warn!("Plugin's GRPCController.Shutdown failed: {}", status);
+        }
+        drop(self.stdin.take());
+
+        match timeout(KILL_GRACE_PERIOD, self.child.wait()).await {
+            Ok(status) => log::info!("Plugin exited with status: {:?}", status?),
+            Err(_) => {
+                log::warn!(
+                    "Plugin didn't exit within {:?} of Shutdown. Killing it.",
+                    KILL_GRACE_PERIOD
+                );
+                self.child.kill().await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tcp;
+    use assert_matches::assert_matches;
+    use tokio::net::TcpListener;
+    use tonic_health::proto::health_client::HealthClient;
+    use tonic_health::proto::HealthCheckRequest;
+
+    fn handshake_config() -> HandshakeConfig {
+        HandshakeConfig {
+            magic_cookie_key: "GRR_TEST_COOKIE".to_string(),
+            magic_cookie_value: "grr".to_string(),
+        }
+    }
+
+    // A plugin that prints the given handshake, if it's given the magic cookie.
+    fn stub(handshake: &str) -> Command {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(format!(
+            "[ \"$GRR_TEST_COOKIE\" = grr ] && echo '{}'",
+            handshake
+        ));
+        cmd
+    }
+
+    #[tokio::test]
+    async fn test_start() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (mut health_reporter, health_service) = tonic_health::server::health_reporter();
+        health_reporter
+            .set_service_status("plugin", tonic_health::ServingStatus::Serving)
+            .await;
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(health_service)
+                .serve_with_incoming(tcp::incoming(listener)),
+        );
+
+        let client = Client::start(
+            stub(&format!("1|1|tcp|{}|grpc|", address)),
+            1,
+            &handshake_config(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(client.handshake().network, "tcp");
+        assert_eq!(client.handshake().address, address);
+        let response = HealthClient::new(client.channel())
+            .check(HealthCheckRequest {
+                service: "plugin".to_string(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(response.into_inner().status, 1);
+    }
+
+    #[tokio::test]
+    async fn test_start_mismatch() {
+        let handshake = "1|1|tcp|127.0.0.1:1|grpc|";
+        let wrong_cookie = HandshakeConfig {
+            magic_cookie_value: "not grr".to_string(),
+            ..handshake_config()
+        };
+        // Without the cookie, the stub exits without a handshake, as go-plugin's plugins do.
+        assert_matches!(
+            Client::start(stub(handshake), 1, &wrong_cookie).await.err(),
+            Some(Error::InvalidHandshake(_))
+        );
+        assert_matches!(
+            Client::start(stub(handshake), 2, &handshake_config())
+                .await
+                .err(),
+            Some(Error::InvalidHandshake(_))
+        );
+        // Plugins speaking anything we don't are turned away before they're dialed.
+        for handshake in [
+            "1|1|tcp|127.0.0.1:1|netrpc|",
+            "1|1|tcp|127.0.0.1:1|grpc|Y2VydA",
+            "1|1|tcp|127.0.0.1:1|grpc||true",
+        ] {
+            assert_matches!(
+                Client::start(stub(handshake), 1, &handshake_config())
+                    .await
+                    .err(),
+                Some(Error::InvalidHandshake(_))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_start_timeout() {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg("sleep 10");
+        assert_matches!(
+            Client::start_within(cmd, 1, &handshake_config(), Duration::from_millis(100))
+                .await
+                .err(),
+            Some(Error::HandshakeTimeout(_))
+        );
+    }
+}
@@ -1,4 +1,6 @@
 use std::fmt::Debug;
+use std::io::ErrorKind;
+use std::path::PathBuf;
 use thiserror::Error as ThisError;
 use tokio::sync::mpsc::error::SendError;
 
@@ -6,8 +8,52 @@ use tonic::transport::Error as TonicError;
 
 use http::uri::InvalidUri;
 
+// Maps errors to the gRPC code closest to why they happened, for the host to act on.
 pub fn into_status(err: Error) -> tonic::Status {
-    tonic::Status::unknown(format!("{}", err))
+    let message = format!("{}", err);
+    match err {
+        Error::ServiceIdInUse(_) => tonic::Status::already_exists(message),
+        Error::DialCancelled(_) => tonic::Status::cancelled(message),
+        Error::HandshakeTimeout(_) | Error::DialTimeout(..) => {
+            tonic::Status::deadline_exceeded(message)
+        }
+        Error::GRPCHandshakeMagicCookieValueMismatch
+        | Error::NoPluginVersions
+        | Error::BrokerStreamAlreadyStarted
+        | Error::AlreadyServed
+        | Error::SetLogger(_) => tonic::Status::failed_precondition(message),
+        Error::InvalidConfig(_)
+        | Error::InvalidLogLevel(_)
+        | Error::InvalidPortRange(_)
+        | Error::InvalidUri(_)
+        | Error::AddrParser(_)
+        | Error::NetworkTypeUnknown(_)
+        | Error::InvalidHandshake(_) => tonic::Status::invalid_argument(message),
+        Error::NoTCPPortAvailable => tonic::Status::resource_exhausted(message),
+        Error::BrokerStreamClosed | Error::Send(_) | Error::TonicTransport(_) => {
+            tonic::Status::unavailable(message)
+        }
+        Error::Io(e) => match e.kind() {
+            ErrorKind::NotFound => tonic::Status::not_found(message),
+            ErrorKind::TimedOut => tonic::Status::deadline_exceeded(message),
+            ErrorKind::PermissionDenied => tonic::Status::permission_denied(message),
+            ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::NotConnected
+            | ErrorKind::BrokenPipe => tonic::Status::unavailable(message),
+            _ => tonic::Status::internal(message),
+        },
+        Error::UnixSocketGroup(_)
+        | Error::SocketPath(_)
+        | Error::Tls(_)
+        | Error::Multiplex(_)
+        | Error::Gob(_)
+        | Error::NetRpc(_)
+        | Error::BrokeredServerPanicked(_) => tonic::Status::internal(message),
+        Error::TestPluginStopped(_) => tonic::Status::unknown(message),
+        Error::Grpc(status) => *status,
+    }
 }
 
 #[derive(Debug, ThisError)]
@@ -16,8 +62,22 @@ pub enum Error {
     NoTCPPortAvailable,
     #[error("This executable is meant to be a go-plugin to other processes. Do not run this directly. The Magic Handshake failed.")]
     GRPCHandshakeMagicCookieValueMismatch,
-    #[error("The requested ServiceId {0} does not exist and timed out waiting for it.")]
-    ServiceIdDoesNotExist(u32),
+    #[error("Timed out after {1:?} waiting for the host's ServiceId {0}.")]
+    DialTimeout(u32, std::time::Duration),
+    #[error("Dialing the host's ServiceId {0} was cancelled.")]
+    DialCancelled(u32),
+    #[error("The ServiceId {0} is already used by another brokered server.")]
+    ServiceIdInUse(u32),
+    #[error("The host already started the broker stream, and it can only be started once.")]
+    BrokerStreamAlreadyStarted,
+    #[error("The host's broker stream has ended, so nothing more can be brokered with it.")]
+    BrokerStreamClosed,
+    #[error("This server has already served a plugin, and can't serve another.")]
+    AlreadyServed,
+    #[error("A brokered server stopped unexpectedly: {0}")]
+    BrokeredServerPanicked(String),
+    #[error("The unix socket path {0:?} is not valid UTF-8.")]
+    SocketPath(PathBuf),
     #[error("Error with IO: {0}")]
     Io(#[from] std::io::Error),
     #[error("Error with tonic (gRPC) transport: {0}")]
@@ -30,8 +90,40 @@ pub enum Error {
     InvalidUri(#[from] InvalidUri),
     #[error("Service endpoint type unknown: {0}")]
     NetworkTypeUnknown(String),
-    #[error(transparent)]
-    Other(#[from] anyhow::Error),
+    #[error("Invalid go-plugin handshake: {0}")]
+    InvalidHandshake(String),
+    #[error("Timed out after {0:?} waiting for the plugin to print its handshake.")]
+    HandshakeTimeout(std::time::Duration),
+    #[error("No plugin sets were provided to negotiate a protocol version from.")]
+    NoPluginVersions,
+    #[error("Invalid plugin port range: {0}")]
+    InvalidPortRange(String),
+    #[error("Unable to give the plugin's unix sockets to the requested group: {0}")]
+    UnixSocketGroup(String),
+    #[error("Error with TLS: {0}")]
+    Tls(String),
+    #[error("Error multiplexing gRPC connections: {0}")]
+    Multiplex(String),
+    #[error("Error encoding or decoding gob: {0}")]
+    Gob(String),
+    #[error("Error serving net/rpc: {0}")]
+    NetRpc(String),
+    #[error("Invalid server configuration: {0}")]
+    InvalidConfig(String),
+    #[error("Unknown log level {0:?}. Expected one of trace, debug, info, warn, error or off.")]
+    InvalidLogLevel(String),
+    #[error("Unable to set the hclog logger: {0}")]
+    SetLogger(#[from] log::SetLoggerError),
+    #[error("A call to the plugin failed: {0}")]
+    Grpc(Box<tonic::Status>),
+    #[error("The test plugin stopped unexpectedly: {0}")]
+    TestPluginStopped(String),
+}
+
+impl From<openssl::error::ErrorStack> for Error {
+    fn from(err: openssl::error::ErrorStack) -> Self {
+        Self::Tls(err.to_string())
+    }
 }
 
 impl<T> From<SendError<T>> for Error {
@@ -42,3 +134,41 @@ impl<T> From<SendError<T>> for Error {
         ))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tonic::Code;
+
+    #[test]
+    fn test_into_status() {
+        assert_eq!(
+            into_status(Error::ServiceIdInUse(3)).code(),
+            Code::AlreadyExists
+        );
+        assert_eq!(
+            into_status(Error::DialTimeout(3, std::time::Duration::from_secs(5))).code(),
+            Code::DeadlineExceeded
+        );
+        assert_eq!(
+            into_status(Error::HandshakeTimeout(std::time::Duration::from_secs(1))).code(),
+            Code::DeadlineExceeded
+        );
+        assert_eq!(
+            into_status(Error::AlreadyServed).code(),
+            Code::FailedPrecondition
+        );
+        assert_eq!(
+            into_status(Error::BrokeredServerPanicked("oops".to_string())).code(),
+            Code::Internal
+        );
+        assert_eq!(
+            into_status(Error::Io(std::io::Error::from(ErrorKind::TimedOut))).code(),
+            Code::DeadlineExceeded
+        );
+        assert_eq!(
+            into_status(Error::Grpc(Box::new(tonic::Status::unavailable("gone")))).code(),
+            Code::Unavailable
+        );
+    }
+}
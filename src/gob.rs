@@ -0,0 +1,938 @@
+// Go's encoding/gob, which net/rpc speaks on the wire.
+// https://pkg.go.dev/encoding/gob
+// Gob streams describe their own types, so values here are dynamically typed.
+// Complex numbers and interface values aren't supported.
+use super::error::Error;
+use std::collections::HashMap;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// Ids of gob's predefined types
+const BOOL_ID: i64 = 1;
+const INT_ID: i64 = 2;
+const UINT_ID: i64 = 3;
+const FLOAT_ID: i64 = 4;
+const BYTES_ID: i64 = 5;
+const STRING_ID: i64 = 6;
+const COMPLEX_ID: i64 = 7;
+const INTERFACE_ID: i64 = 8;
+
+// The first id Go hands out to types it defines on the wire
+const FIRST_USER_ID: i64 = 65;
+
+// Refuse messages larger than Go would send, rather than allocate whatever a corrupt length says.
+const MAX_MESSAGE_SIZE: u64 = 1 << 30;
+
+// How deeply types may nest. Go allows recursive types, which can't be represented here.
+const MAX_TYPE_DEPTH: usize = 64;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Type {
+    // Go's bool
+    Bool,
+    // Any of Go's signed integers
+    Int,
+    // Any of Go's unsigned integers
+    Uint,
+    // float32 or float64
+    Float,
+    // []byte, and values of types implementing GobEncoder or the marshaler interfaces
+    Bytes,
+    String,
+    Array(Box<Type>, usize),
+    Slice(Box<Type>),
+    Map(Box<Type>, Box<Type>),
+    // A struct's name, and its exported fields in order. Go matches fields by name.
+    Struct(String, Vec<(String, Type)>),
+}
+
+impl Type {
+    pub fn zero(&self) -> Value {
+        match self {
+            Type::Bool => Value::Bool(false),
+            Type::Int => Value::Int(0),
+            Type::Uint => Value::Uint(0),
+            Type::Float => Value::Float(0.0),
+            Type::Bytes => Value::Bytes(Vec::new()),
+            Type::String => Value::String(String::new()),
+            Type::Array(elem, len) => Value::Array(elem.as_ref().clone(), vec![elem.zero(); *len]),
+            Type::Slice(elem) => Value::Slice(elem.as_ref().clone(), Vec::new()),
+            Type::Map(key, elem) => {
+                Value::Map(key.as_ref().clone(), elem.as_ref().clone(), Vec::new())
+            }
+            Type::Struct(name, fields) => Value::Struct(
+                name.clone(),
+                fields
+                    .iter()
+                    .map(|(name, ty)| (name.clone(), ty.zero()))
+                    .collect(),
+            ),
+        }
+    }
+
+    // How many values zero allocates, which is how many bytes sending them takes at least.
+    fn zero_len(&self) -> usize {
+        match self {
+            Type::Array(elem, len) => len.saturating_mul(elem.zero_len()),
+            Type::Struct(_, fields) => fields
+                .iter()
+                .fold(1, |sum, (_, ty)| sum.saturating_add(ty.zero_len())),
+            _ => 1,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Bool(bool),
+    Int(i64),
+    Uint(u64),
+    Float(f64),
+    Bytes(Vec<u8>),
+    String(String),
+    // Collections carry their element types, since they may be empty.
+    Array(Type, Vec<Value>),
+    Slice(Type, Vec<Value>),
+    Map(Type, Type, Vec<(Value, Value)>),
+    Struct(String, Vec<(String, Value)>),
+}
+
+impl Value {
+    // What Go's struct{}{} looks like, which net/rpc uses for replies that carry nothing.
+    pub fn empty_struct() -> Self {
+        Value::Struct(String::new(), Vec::new())
+    }
+
+    pub fn ty(&self) -> Type {
+        match self {
+            Value::Bool(_) => Type::Bool,
+            Value::Int(_) => Type::Int,
+            Value::Uint(_) => Type::Uint,
+            Value::Float(_) => Type::Float,
+            Value::Bytes(_) => Type::Bytes,
+            Value::String(_) => Type::String,
+            Value::Array(elem, items) => Type::Array(Box::new(elem.clone()), items.len()),
+            Value::Slice(elem, _) => Type::Slice(Box::new(elem.clone())),
+            Value::Map(key, elem, _) => Type::Map(Box::new(key.clone()), Box::new(elem.clone())),
+            Value::Struct(name, fields) => Type::Struct(
+                name.clone(),
+                fields
+                    .iter()
+                    .map(|(name, value)| (name.clone(), value.ty()))
+                    .collect(),
+            ),
+        }
+    }
+
+    // A struct's field by name
+    pub fn field(&self, name: &str) -> Option<&Value> {
+        match self {
+            Value::Struct(_, fields) => fields.iter().find(|(n, _)| n == name).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    // Struct fields holding these aren't sent. Arrays and structs always are.
+    fn is_zero(&self) -> bool {
+        match self {
+            Value::Bool(b) => !b,
+            Value::Int(i) => *i == 0,
+            Value::Uint(u) => *u == 0,
+            Value::Float(f) => *f == 0.0,
+            Value::Bytes(b) => b.is_empty(),
+            Value::String(s) => s.is_empty(),
+            Value::Slice(_, items) => items.is_empty(),
+            Value::Map(_, _, entries) => entries.is_empty(),
+            Value::Array(..) | Value::Struct(..) => false,
+        }
+    }
+}
+
+// Encodes values onto one stream. Each type is defined on the wire once, before its first use.
+pub struct Encoder {
+    sent: HashMap<Type, i64>,
+    next_id: i64,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Self {
+            sent: HashMap::new(),
+            next_id: FIRST_USER_ID,
+        }
+    }
+
+    // The messages to write for this value.
+    pub fn encode(&mut self, value: &Value) -> Vec<u8> {
+        let mut out = Vec::new();
+        let id = self.define(&value.ty(), &mut out);
+
+        let mut message = Vec::new();
+        put_int(&mut message, id);
+        // Anything but a struct is sent like a struct's only field.
+        if !matches!(value, Value::Struct(..)) {
+            put_uint(&mut message, 0);
+        }
+        encode_value(&mut message, value);
+
+        put_message(&mut out, &message);
+        out
+    }
+
+    // The id of this type, after writing the definitions of it and the types it's made of,
+    // when they weren't sent before.
+    fn define(&mut self, ty: &Type, out: &mut Vec<u8>) -> i64 {
+        match ty {
+            Type::Bool => return BOOL_ID,
+            Type::Int => return INT_ID,
+            Type::Uint => return UINT_ID,
+            Type::Float => return FLOAT_ID,
+            Type::Bytes => return BYTES_ID,
+            Type::String => return STRING_ID,
+            _ => {}
+        }
+        if let Some(id) = self.sent.get(ty) {
+            return *id;
+        }
+
+        // wireType's fields are ArrayT, SliceT, StructT and MapT, in that order,
+        // and each of those starts with CommonType{Name, Id}.
+        let mut wire = Vec::new();
+        let id = match ty {
+            Type::Array(elem, len) => {
+                let elem = self.define(elem, out);
+                let id = self.next_id();
+                let mut w = FieldWriter::new(&mut wire);
+                let mut array = FieldWriter::new(w.field(0));
+                put_common_type(array.field(0), "", id);
+                put_int(array.field(1), elem);
+                if *len > 0 {
+                    put_int(array.field(2), *len as i64);
+                }
+                array.end();
+                id
+            }
+            Type::Slice(elem) => {
+                let elem = self.define(elem, out);
+                let id = self.next_id();
+                let mut w = FieldWriter::new(&mut wire);
+                let mut slice = FieldWriter::new(w.field(1));
+                put_common_type(slice.field(0), "", id);
+                put_int(slice.field(1), elem);
+                slice.end();
+                id
+            }
+            Type::Map(key, elem) => {
+                let key = self.define(key, out);
+                let elem = self.define(elem, out);
+                let id = self.next_id();
+                let mut w = FieldWriter::new(&mut wire);
+                let mut map = FieldWriter::new(w.field(3));
+                put_common_type(map.field(0), "", id);
+                put_int(map.field(1), key);
+                put_int(map.field(2), elem);
+                map.end();
+                id
+            }
+            Type::Struct(name, fields) => {
+                let field_ids: Vec<i64> =
+                    fields.iter().map(|(_, ty)| self.define(ty, out)).collect();
+                let id = self.next_id();
+                let mut w = FieldWriter::new(&mut wire);
+                let mut st = FieldWriter::new(w.field(2));
+                put_common_type(st.field(0), name, id);
+                if !fields.is_empty() {
+                    let buf = st.field(1);
+                    put_uint(buf, fields.len() as u64);
+                    for ((name, _), id) in fields.iter().zip(field_ids) {
+                        let mut field = FieldWriter::new(&mut *buf);
+                        put_string(field.field(0), name);
+                        put_int(field.field(1), id);
+                        field.end();
+                    }
+                }
+                st.end();
+                id
+            }
+            _ => unreachable!("predefined types returned above"),
+        };
+        // ends the wireType
+        put_uint(&mut wire, 0);
+
+        let mut message = Vec::new();
+        put_int(&mut message, -id);
+        message.extend(wire);
+        put_message(out, &message);
+
+        self.sent.insert(ty.clone(), id);
+        id
+    }
+
+    fn next_id(&mut self) -> i64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+}
+
+impl Default for Encoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Decodes values from one stream, remembering the types it defines.
+#[derive(Default)]
+pub struct Decoder {
+    types: HashMap<i64, WireType>,
+}
+
+#[derive(Clone, Debug)]
+enum WireType {
+    Array(i64, usize),
+    Slice(i64),
+    Struct(String, Vec<(String, i64)>),
+    Map(i64, i64),
+    // Types that encode themselves, which arrive as bytes
+    Opaque,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Reads the next value, and any type definitions before it.
+    pub async fn decode<R>(&mut self, reader: &mut R) -> Result<Value, Error>
+    where
+        R: AsyncRead + Unpin,
+    {
+        loop {
+            let len = read_uint(reader).await?;
+            if len > MAX_MESSAGE_SIZE {
+                return Err(Error::Gob(format!("message of {} bytes is too big", len)));
+            }
+            let mut message = vec![0u8; len as usize];
+            reader.read_exact(&mut message).await?;
+
+            let mut buf = Buf::new(&message);
+            let id = buf.int()?;
+            if id < 0 {
+                let wire = read_wire_type(&mut buf)?;
+                log::trace!("gob type {} defined as {:?}", -id, wire);
+                self.types.insert(-id, wire);
+                continue;
+            }
+
+            // Anything but a struct arrives like a struct's only field.
+            if !matches!(self.types.get(&id), Some(WireType::Struct(..))) && buf.uint()? != 0 {
+                return Err(Error::Gob(
+                    "corrupted data: non-zero delta for singleton".to_string(),
+                ));
+            }
+            return self.decode_value(id, &mut buf, 0);
+        }
+    }
+
+    fn wire_type(&self, id: i64) -> Result<&WireType, Error> {
+        self.types
+            .get(&id)
+            .ok_or_else(|| Error::Gob(format!("type id {} was never defined", id)))
+    }
+
+    fn type_of(&self, id: i64, depth: usize) -> Result<Type, Error> {
+        if depth > MAX_TYPE_DEPTH {
+            return Err(Error::Gob(format!("type {} nests too deeply", id)));
+        }
+        Ok(match id {
+            BOOL_ID => Type::Bool,
+            INT_ID => Type::Int,
+            UINT_ID => Type::Uint,
+            FLOAT_ID => Type::Float,
+            BYTES_ID => Type::Bytes,
+            STRING_ID => Type::String,
+            COMPLEX_ID | INTERFACE_ID => return Err(unsupported(id)),
+            _ => match self.wire_type(id)? {
+                WireType::Array(elem, len) => {
+                    Type::Array(Box::new(self.type_of(*elem, depth + 1)?), *len)
+                }
+                WireType::Slice(elem) => Type::Slice(Box::new(self.type_of(*elem, depth + 1)?)),
+                WireType::Map(key, elem) => Type::Map(
+                    Box::new(self.type_of(*key, depth + 1)?),
+                    Box::new(self.type_of(*elem, depth + 1)?),
+                ),
+                WireType::Struct(name, fields) => Type::Struct(
+                    name.clone(),
+                    fields
+                        .iter()
+                        .map(|(name, id)| Ok((name.clone(), self.type_of(*id, depth + 1)?)))
+                        .collect::<Result<_, Error>>()?,
+                ),
+                WireType::Opaque => Type::Bytes,
+            },
+        })
+    }
+
+    fn decode_value(&self, id: i64, buf: &mut Buf, depth: usize) -> Result<Value, Error> {
+        if depth > MAX_TYPE_DEPTH {
+            return Err(Error::Gob(format!("value of type {} nests too deeply", id)));
+        }
+        Ok(match id {
+            BOOL_ID => Value::Bool(buf.uint()? != 0),
+            INT_ID => Value::Int(buf.int()?),
+            UINT_ID => Value::Uint(buf.uint()?),
+            FLOAT_ID => Value::Float(f64::from_bits(buf.uint()?.swap_bytes())),
+            BYTES_ID => Value::Bytes(buf.bytes()?.to_vec()),
+            STRING_ID => Value::String(
+                String::from_utf8(buf.bytes()?.to_vec())
+                    .map_err(|e| Error::Gob(format!("string is not utf-8: {}", e)))?,
+            ),
+            COMPLEX_ID | INTERFACE_ID => return Err(unsupported(id)),
+            _ => match self.wire_type(id)?.clone() {
+                WireType::Array(elem, len) => {
+                    let count = buf.len()?;
+                    if count != len {
+                        return Err(Error::Gob(format!(
+                            "array of length {} sent with {} elements",
+                            len, count
+                        )));
+                    }
+                    let items = (0..count)
+                        .map(|_| self.decode_value(elem, buf, depth + 1))
+                        .collect::<Result<_, _>>()?;
+                    Value::Array(self.type_of(elem, depth + 1)?, items)
+                }
+                WireType::Slice(elem) => {
+                    let count = buf.len()?;
+                    let items = (0..count)
+                        .map(|_| self.decode_value(elem, buf, depth + 1))
+                        .collect::<Result<_, _>>()?;
+                    Value::Slice(self.type_of(elem, depth + 1)?, items)
+                }
+                WireType::Map(key, elem) => {
+                    let count = buf.len()?;
+                    let entries = (0..count)
+                        .map(|_| {
+                            Ok((
+                                self.decode_value(key, buf, depth + 1)?,
+                                self.decode_value(elem, buf, depth + 1)?,
+                            ))
+                        })
+                        .collect::<Result<_, Error>>()?;
+                    Value::Map(
+                        self.type_of(key, depth + 1)?,
+                        self.type_of(elem, depth + 1)?,
+                        entries,
+                    )
+                }
+                WireType::Struct(name, fields) => {
+                    let mut values: Vec<Option<Value>> = vec![None; fields.len()];
+                    read_struct(buf, |number, buf| {
+                        let (_, id) = fields.get(number).ok_or_else(|| {
+                            Error::Gob(format!("field {} out of range for {}", number, name))
+                        })?;
+                        values[number] = Some(self.decode_value(*id, buf, depth + 1)?);
+                        Ok(())
+                    })?;
+
+                    // Fields holding zero values aren't sent. Arrays always are, so one missing
+                    // is only filled in when it's no bigger than a message that sent it could be.
+                    let fields = fields
+                        .into_iter()
+                        .zip(values)
+                        .map(|((name, id), value)| {
+                            let value = match value {
+                                Some(value) => value,
+                                None => {
+                                    let ty = self.type_of(id, depth + 1)?;
+                                    if ty.zero_len() > buf.size {
+                                        return Err(Error::Gob(format!(
+                                            "field {} is too big to be missing from a message of {} bytes",
+                                            name, buf.size
+                                        )));
+                                    }
+                                    ty.zero()
+                                }
+                            };
+                            Ok((name, value))
+                        })
+                        .collect::<Result<_, Error>>()?;
+                    Value::Struct(name, fields)
+                }
+                WireType::Opaque => Value::Bytes(buf.bytes()?.to_vec()),
+            },
+        })
+    }
+}
+
+fn unsupported(id: i64) -> Error {
+    Error::Gob(format!(
+        "values of type {} (complex or interface) are not supported",
+        id
+    ))
+}
+
+// Parses a wireType, the definition of a type.
+fn read_wire_type(buf: &mut Buf) -> Result<WireType, Error> {
+    let mut wire = None;
+    read_struct(buf, |number, buf| {
+        wire = Some(match number {
+            // ArrayT
+            0 => {
+                let (mut elem, mut len) = (0, 0);
+                read_struct(buf, |number, buf| {
+                    match number {
+                        0 => skip_common_type(buf)?,
+                        1 => elem = buf.int()?,
+                        2 => len = buf.int()?,
+                        _ => return Err(unknown_field("arrayType", number)),
+                    };
+                    Ok(())
+                })?;
+                // An array's elements are sent in one message, so it can't be longer than one.
+                if !(0..=MAX_MESSAGE_SIZE as i64).contains(&len) {
+                    return Err(Error::Gob(format!("invalid array length {}", len)));
+                }
+                WireType::Array(elem, len as usize)
+            }
+            // SliceT
+            1 => {
+                let mut elem = 0;
+                read_struct(buf, |number, buf| {
+                    match number {
+                        0 => skip_common_type(buf)?,
+                        1 => elem = buf.int()?,
+                        _ => return Err(unknown_field("sliceType", number)),
+                    };
+                    Ok(())
+                })?;
+                WireType::Slice(elem)
+            }
+            // StructT
+            2 => {
+                let mut name = String::new();
+                let mut fields = Vec::new();
+                read_struct(buf, |number, buf| {
+                    match number {
+                        0 => read_struct(buf, |number, buf| {
+                            match number {
+                                0 => name = buf.string()?,
+                                1 => {
+                                    buf.int()?;
+                                }
+                                _ => return Err(unknown_field("CommonType", number)),
+                            };
+                            Ok(())
+                        })?,
+                        1 => {
+                            for _ in 0..buf.len()? {
+                                let (mut field_name, mut id) = (String::new(), 0);
+                                read_struct(buf, |number, buf| {
+                                    match number {
+                                        0 => field_name = buf.string()?,
+                                        1 => id = buf.int()?,
+                                        _ => return Err(unknown_field("fieldType", number)),
+                                    };
+                                    Ok(())
+                                })?;
+                                fields.push((field_name, id));
+                            }
+                        }
+                        _ => return Err(unknown_field("structType", number)),
+                    };
+                    Ok(())
+                })?;
+                WireType::Struct(name, fields)
+            }
+            // MapT
+            3 => {
+                let (mut key, mut elem) = (0, 0);
+                read_struct(buf, |number, buf| {
+                    match number {
+                        0 => skip_common_type(buf)?,
+                        1 => key = buf.int()?,
+                        2 => elem = buf.int()?,
+                        _ => return Err(unknown_field("mapType", number)),
+                    };
+                    Ok(())
+                })?;
+                WireType::Map(key, elem)
+            }
+            // GobEncoderT, BinaryMarshalerT and TextMarshalerT
+            4..=6 => {
+                read_struct(buf, |number, buf| match number {
+                    0 => skip_common_type(buf),
+                    _ => Err(unknown_field("gobEncoderType", number)),
+                })?;
+                WireType::Opaque
+            }
+            _ => return Err(unknown_field("wireType", number)),
+        });
+        Ok(())
+    })?;
+
+    wire.ok_or_else(|| Error::Gob("empty type definition".to_string()))
+}
+
+fn skip_common_type(buf: &mut Buf) -> Result<(), Error> {
+    read_struct(buf, |number, buf| {
+        match number {
+            0 => {
+                buf.bytes()?;
+            }
+            1 => {
+                buf.int()?;
+            }
+            _ => return Err(unknown_field("CommonType", number)),
+        };
+        Ok(())
+    })
+}
+
+fn unknown_field(type_name: &str, number: usize) -> Error {
+    Error::Gob(format!("unknown field {} in {}", number, type_name))
+}
+
+// Calls f with each field number that was sent, leaving it to read the field's value.
+fn read_struct<F>(buf: &mut Buf, mut f: F) -> Result<(), Error>
+where
+    F: FnMut(usize, &mut Buf) -> Result<(), Error>,
+{
+    let mut number: Option<usize> = None;
+    loop {
+        let delta = buf.uint()? as usize;
+        if delta == 0 {
+            return Ok(());
+        }
+        let next = match number {
+            None => delta - 1,
+            Some(n) => n + delta,
+        };
+        number = Some(next);
+        f(next, buf)?;
+    }
+}
+
+fn encode_value(buf: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Bool(b) => put_uint(buf, *b as u64),
+        Value::Int(i) => put_int(buf, *i),
+        Value::Uint(u) => put_uint(buf, *u),
+        Value::Float(f) => put_uint(buf, f.to_bits().swap_bytes()),
+        Value::Bytes(b) => {
+            put_uint(buf, b.len() as u64);
+            buf.extend_from_slice(b);
+        }
+        Value::String(s) => put_string(buf, s),
+        Value::Array(_, items) | Value::Slice(_, items) => {
+            put_uint(buf, items.len() as u64);
+            for item in items {
+                encode_value(buf, item);
+            }
+        }
+        Value::Map(_, _, entries) => {
+            put_uint(buf, entries.len() as u64);
+            for (key, elem) in entries {
+                encode_value(buf, key);
+                encode_value(buf, elem);
+            }
+        }
+        Value::Struct(_, fields) => {
+            let mut w = FieldWriter::new(buf);
+            for (number, (_, value)) in fields.iter().enumerate() {
+                if !value.is_zero() {
+                    encode_value(w.field(number), value);
+                }
+            }
+            w.end();
+        }
+    }
+}
+
+// Writes a struct's fields. Each is preceded by the delta from the previous field's number.
+struct FieldWriter<'a> {
+    buf: &'a mut Vec<u8>,
+    last: i64,
+}
+
+impl<'a> FieldWriter<'a> {
+    fn new(buf: &'a mut Vec<u8>) -> Self {
+        Self { buf, last: -1 }
+    }
+
+    fn field(&mut self, number: usize) -> &mut Vec<u8> {
+        put_uint(self.buf, (number as i64 - self.last) as u64);
+        self.last = number as i64;
+        self.buf
+    }
+
+    fn end(self) {
+        put_uint(self.buf, 0);
+    }
+}
+
+fn put_common_type(buf: &mut Vec<u8>, name: &str, id: i64) {
+    let mut w = FieldWriter::new(buf);
+    if !name.is_empty() {
+        put_string(w.field(0), name);
+    }
+    put_int(w.field(1), id);
+    w.end();
+}
+
+fn put_message(out: &mut Vec<u8>, message: &[u8]) {
+    put_uint(out, message.len() as u64);
+    out.extend_from_slice(message);
+}
+
+// Small numbers are a byte. Others are their big-endian bytes, preceded by the negated byte count.
+fn put_uint(buf: &mut Vec<u8>, u: u64) {
+    if u < 0x80 {
+        buf.push(u as u8);
+        return;
+    }
+    let bytes = u.to_be_bytes();
+    let skip = (u.leading_zeros() / 8) as usize;
+    buf.push((-((8 - skip) as i8)) as u8);
+    buf.extend_from_slice(&bytes[skip..]);
+}
+
+// The sign is moved to the lowest bit, and the rest complemented for negative numbers.
+fn put_int(buf: &mut Vec<u8>, i: i64) {
+    let u = if i < 0 {
+        ((!i as u64) << 1) | 1
+    } else {
+        (i as u64) << 1
+    };
+    put_uint(buf, u);
+}
+
+fn put_string(buf: &mut Vec<u8>, s: &str) {
+    put_uint(buf, s.len() as u64);
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn int_from_uint(u: u64) -> i64 {
+    if u & 1 == 1 {
+        !(u >> 1) as i64
+    } else {
+        (u >> 1) as i64
+    }
+}
+
+// Only message lengths are read straight from the stream. Everything else is read from a message.
+async fn read_uint<R>(reader: &mut R) -> Result<u64, Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let first = reader.read_u8().await?;
+    if first < 0x80 {
+        return Ok(first as u64);
+    }
+    let count = first.wrapping_neg() as usize;
+    if count > 8 {
+        return Err(Error::Gob(format!("invalid uint byte count {}", count)));
+    }
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes[8 - count..]).await?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+struct Buf<'a> {
+    data: &'a [u8],
+    // The whole message's length, not just what's left of it
+    size: usize,
+}
+
+impl<'a> Buf<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            size: data.len(),
+        }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if n > self.data.len() {
+            return Err(Error::Gob(format!(
+                "unexpected end of message reading {} bytes",
+                n
+            )));
+        }
+        let (taken, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn uint(&mut self) -> Result<u64, Error> {
+        let first = self.take(1)?[0];
+        if first < 0x80 {
+            return Ok(first as u64);
+        }
+        let count = first.wrapping_neg() as usize;
+        if count > 8 {
+            return Err(Error::Gob(format!("invalid uint byte count {}", count)));
+        }
+        let mut bytes = [0u8; 8];
+        bytes[8 - count..].copy_from_slice(self.take(count)?);
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    fn int(&mut self) -> Result<i64, Error> {
+        Ok(int_from_uint(self.uint()?))
+    }
+
+    // A count of elements, each of which takes at least a byte.
+    fn len(&mut self) -> Result<usize, Error> {
+        let len = self.uint()?;
+        if len > self.data.len() as u64 {
+            return Err(Error::Gob(format!(
+                "length {} is longer than the rest of the message",
+                len
+            )));
+        }
+        Ok(len as usize)
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.len()?;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        String::from_utf8(self.bytes()?.to_vec())
+            .map_err(|e| Error::Gob(format!("string is not utf-8: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(service_method: &str, seq: u64) -> Value {
+        Value::Struct(
+            "Request".to_string(),
+            vec![
+                (
+                    "ServiceMethod".to_string(),
+                    Value::String(service_method.to_string()),
+                ),
+                ("Seq".to_string(), Value::Uint(seq)),
+            ],
+        )
+    }
+
+    #[test]
+    fn test_encode_request() {
+        let mut encoder = Encoder::new();
+        let encoded = encoder.encode(&request("Control.Ping", 1));
+
+        // The definition of net/rpc's Request, as Go sends it.
+        let mut expected = vec![
+            0x2f, 0xff, 0x81, 0x03, 0x01, 0x01, 0x07, b'R', b'e', b'q', b'u', b'e', b's', b't',
+            0x01, 0xff, 0x82, 0x00, 0x01, 0x02, 0x01, 0x0d,
+        ];
+        expected.extend_from_slice(b"ServiceMethod");
+        expected.extend_from_slice(&[0x01, 0x0c, 0x00, 0x01, 0x03]);
+        expected.extend_from_slice(b"Seq");
+        expected.extend_from_slice(&[0x01, 0x06, 0x00, 0x00, 0x00]);
+        // and the value
+        expected.extend_from_slice(&[0x13, 0xff, 0x82, 0x01, 0x0c]);
+        expected.extend_from_slice(b"Control.Ping");
+        expected.extend_from_slice(&[0x01, 0x01, 0x00]);
+        assert_eq!(expected, encoded);
+
+        // The type is only defined once.
+        let encoded = encoder.encode(&request("Control.Quit", 0));
+        assert_eq!(0x11, encoded[0]);
+        assert_eq!(0x12, encoded.len());
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip() {
+        let values = vec![
+            Value::Bool(true),
+            Value::Int(-129),
+            Value::Uint(u64::MAX),
+            Value::Float(1.5),
+            Value::String("hello".to_string()),
+            Value::Bytes(vec![0, 1, 2]),
+            request("Plugin.Echo", 0),
+            Value::Slice(Type::String, vec![Value::String("a".to_string())]),
+            Value::Map(
+                Type::String,
+                Type::Slice(Box::new(Type::Int)),
+                vec![(
+                    Value::String("k".to_string()),
+                    Value::Slice(Type::Int, vec![Value::Int(7), Value::Int(-7)]),
+                )],
+            ),
+            Value::Array(Type::Uint, vec![Value::Uint(1), Value::Uint(0)]),
+            Value::Struct(
+                "Outer".to_string(),
+                vec![
+                    ("Inner".to_string(), request("", 0)),
+                    (
+                        "Items".to_string(),
+                        Value::Slice(request("", 0).ty(), vec![request("x", 3)]),
+                    ),
+                ],
+            ),
+            Value::empty_struct(),
+        ];
+
+        let mut encoder = Encoder::new();
+        let mut stream = Vec::new();
+        for value in values.iter() {
+            stream.extend(encoder.encode(value));
+        }
+
+        let mut decoder = Decoder::new();
+        let mut reader = stream.as_slice();
+        for value in values.iter() {
+            assert_eq!(value, &decoder.decode(&mut reader).await.unwrap());
+        }
+        assert!(decoder.decode(&mut reader).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_decode_corrupt() {
+        // A byte count of 128, rather than a negated count of up to 8
+        let mut reader: &[u8] = &[0x80];
+        assert!(Decoder::new().decode(&mut reader).await.is_err());
+        let mut reader: &[u8] = &[0x02, 0x01, 0x80];
+        assert!(Decoder::new().decode(&mut reader).await.is_err());
+
+        // Type 65 defined as an array of -1 ints
+        let mut reader: &[u8] = &[0x09, 0xff, 0x81, 0x01, 0x02, 0x04, 0x01, 0x01, 0x00, 0x00];
+        let err = Decoder::new().decode(&mut reader).await.unwrap_err();
+        assert!(
+            err.to_string().contains("invalid array length -1"),
+            "{}",
+            err
+        );
+
+        // A struct whose array of 1<<29 ints is missing from a message of a few bytes
+        let mut stream = Vec::new();
+        let huge = Type::Struct(
+            "Huge".to_string(),
+            vec![(
+                "Items".to_string(),
+                Type::Array(Box::new(Type::Int), 1 << 29),
+            )],
+        );
+        let id = Encoder::new().define(&huge, &mut stream);
+        let mut message = Vec::new();
+        put_int(&mut message, id);
+        put_uint(&mut message, 0);
+        put_message(&mut stream, &message);
+        let err = Decoder::new()
+            .decode(&mut stream.as_slice())
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("field Items is too big"),
+            "{}",
+            err
+        );
+    }
+}
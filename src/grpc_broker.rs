@@ -1,23 +1,29 @@
 // Because of course something using Golang and gRPC has to be overtly complex in new and innovative ways.
 // The secondary streams brokered by GRPC Broker are JSON-RPC 2.0, wouldn't you know?
+use super::brokered_client::{BrokeredChannel, BrokeredClient, Interceptors};
+use super::grpc_broker_service::grpc_plugins::conn_info::Knock;
+use super::grpc_mux::GRpcServerMuxer;
+use super::shutdown::Drain;
+use super::tls::{self, AutoMtls};
+use super::transport::{self, Connection, ListenConfig};
 use super::unique_port::UniquePort;
-use super::unix::{incoming_from_path, TempSocket};
+use super::unix;
 use super::Error;
 use super::ServiceId;
 use super::{ConnInfo, Status};
-use anyhow::anyhow;
-use anyhow::{Context, Result};
-use async_recursion::async_recursion;
+use futures::future::{self, Future};
 use futures::stream::StreamExt;
 use hyper::{Body, Request, Response};
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::net::UnixStream;
+use tokio::net::{TcpStream, UnixStream};
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
-use tokio::sync::Mutex;
-use tokio::time::sleep;
+use tokio::sync::{oneshot, Mutex, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
 use tonic::body::BoxBody;
 use tonic::transport::NamedService;
 use tonic::transport::{Channel, Endpoint, Uri};
@@ -25,38 +31,304 @@ use tonic::Streaming;
 use tower::service_fn as tower_service_fn;
 use tower::Service;
 
+// How long to wait for the host to answer a knock. Same as go-plugin.
+const KNOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long dial_to_host_service waits for the host to tell us about a service. Same as go-plugin.
+pub const DIAL_TIMEOUT: Duration = Duration::from_secs(5);
+
+type KnockAcks = Arc<Mutex<HashMap<ServiceId, oneshot::Sender<Knock>>>>;
+
+type UsedIds = Arc<std::sync::Mutex<ServiceIds>>;
+
+// The service_ids of our brokered servers.
+struct ServiceIds {
+    used: HashSet<ServiceId>,
+    next: ServiceId,
+}
+
+impl ServiceIds {
+    fn new() -> Self {
+        Self {
+            used: HashSet::new(),
+            next: 1, // start next id at a number where it won't conflict with other services
+        }
+    }
+
+    fn next_unused(&mut self) -> ServiceId {
+        // keep incrementing next so long as it has already been used.
+        while self.used.contains(&self.next) {
+            self.next += 1;
+        }
+        self.next
+    }
+}
+
+// Services on the host-side that we've been informed of, kept so they can be dialed again
+// until they're forgotten.
+#[derive(Default)]
+struct HostServices {
+    conn_infos: Mutex<HashMap<ServiceId, ConnInfo>>,
+    // Wakes dials waiting for the host to tell us about their service.
+    published: Notify,
+}
+
+impl HostServices {
+    // The host may move a service, e.g. after restarting it, so the latest ConnInfo wins.
+    async fn publish(&self, conn_info: ConnInfo) {
+        self.conn_infos
+            .lock()
+            .await
+            .insert(conn_info.service_id, conn_info);
+        self.published.notify_waiters();
+    }
+
+    async fn get(&self, service_id: ServiceId) -> Option<ConnInfo> {
+        self.conn_infos.lock().await.get(&service_id).cloned()
+    }
+
+    async fn forget(&self, service_id: ServiceId) -> bool {
+        self.conn_infos.lock().await.remove(&service_id).is_some()
+    }
+
+    // Waits for the host to tell us about the service, however long that takes.
+    async fn wait_for(&self, service_id: ServiceId) -> ConnInfo {
+        loop {
+            // Registered before looking, so a ConnInfo published in between still wakes us.
+            let published = self.published.notified();
+            tokio::pin!(published);
+            published.as_mut().enable();
+
+            if let Some(conn_info) = self.get(service_id).await {
+                return conn_info;
+            }
+            log::trace!("Waiting for the host to publish service {}", service_id);
+            published.await;
+        }
+    }
+}
+
+// Connects to the host's brokered servers, knocking on them when multiplexed, or wherever the
+// host last told us they are otherwise.
+#[derive(Clone)]
+struct HostDialer {
+    host_services: Arc<HostServices>,
+    muxer: Option<GRpcServerMuxer>,
+    knock_acks: KnockAcks,
+    // Held from knocking until the stream is open, since the host hands its streams to
+    // knocks in the order they came.
+    dialing: Arc<Mutex<()>>,
+    outgoing_conninfo_sender: UnboundedSender<Result<ConnInfo, Status>>,
+    auto_mtls: Option<AutoMtls>,
+}
+
+impl HostDialer {
+    async fn dial(&self, service_id: ServiceId, wait: Duration) -> Result<Channel, Error> {
+        if self.muxer.is_some() {
+            // Each connection knocks first, including reconnects.
+            let host = self.clone();
+            let channel = Endpoint::try_from("http://[::]:50051")?
+                .connect_with_connector(tower_service_fn(move |_: Uri| {
+                    let host = host.clone();
+                    async move {
+                        host.connect(service_id, wait)
+                            .await
+                            .map_err(std::io::Error::other)
+                    }
+                }))
+                .await?;
+            return Ok(channel);
+        }
+
+        let conn_info = timeout(wait, self.host_services.wait_for(service_id))
+            .await
+            .map_err(|_| Error::DialTimeout(service_id, wait))?;
+
+        dial(conn_info, self.auto_mtls.clone()).await
+    }
+
+    // Opens a new connection to the service, looking up where it is each time.
+    async fn connect(
+        &self,
+        service_id: ServiceId,
+        wait: Duration,
+    ) -> Result<tls::MaybeTlsStream<Connection>, Error> {
+        let io = match &self.muxer {
+            Some(muxer) => {
+                let _dialing = self.dialing.lock().await;
+                self.knock(service_id).await?;
+                muxer.dial().await?
+            }
+            None => {
+                let conn_info = timeout(wait, self.host_services.wait_for(service_id))
+                    .await
+                    .map_err(|_| Error::DialTimeout(service_id, wait))?;
+                log::debug!(
+                    "Connecting to host service {} at {}:{}",
+                    service_id,
+                    conn_info.network,
+                    conn_info.address
+                );
+                match conn_info.network.as_str() {
+                    "tcp" => Connection::Tcp(TcpStream::connect(conn_info.address).await?),
+                    "unix" => Connection::Unix(unix::UnixStream(
+                        UnixStream::connect(conn_info.address).await?,
+                    )),
+                    s => return Err(Error::NetworkTypeUnknown(s.to_string())),
+                }
+            }
+        };
+
+        Ok(connect_io(io, self.auto_mtls.clone()).await?)
+    }
+
+    // Asks the host to send the next stream we open on the multiplexed connection
+    // to its brokered server with this service_id. Callers hold dialing until they've opened it.
+    // Copied from: https://github.com/hashicorp/go-plugin/blob/master/internal/grpcmux/grpc_client_muxer.go
+    async fn knock(&self, service_id: ServiceId) -> Result<(), Error> {
+        let (ack_sender, ack_receiver) = oneshot::channel();
+        self.knock_acks.lock().await.insert(service_id, ack_sender);
+
+        log::debug!("knocking on host service {}", service_id);
+        self.outgoing_conninfo_sender
+            .send(Ok(ConnInfo {
+                service_id,
+                knock: Some(Knock {
+                    knock: true,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }))
+            .map_err(|_| Error::BrokerStreamClosed)?;
+
+        match timeout(KNOCK_TIMEOUT, ack_receiver).await {
+            Ok(Ok(knock)) if knock.ack => Ok(()),
+            Ok(Ok(knock)) => Err(Error::Multiplex(format!(
+                "failed to knock for id {}: {}",
+                service_id, knock.error
+            ))),
+            Ok(Err(_)) => Err(Error::Multiplex(format!(
+                "stopped waiting for the host to answer the knock for id {}",
+                service_id
+            ))),
+            Err(_) => {
+                self.knock_acks.lock().await.remove(&service_id);
+                Err(Error::DialTimeout(service_id, KNOCK_TIMEOUT))
+            }
+        }
+    }
+}
+
+// A server brokered to the host, served until it's closed or the plugin shuts down. Dropping
+// this leaves it serving.
+pub struct BrokeredServer {
+    service_id: ServiceId,
+    close_trigger: triggered::Trigger,
+    serving: JoinHandle<Result<(), Error>>,
+    used_ids: UsedIds,
+    // The tcp port it listens on, vended until it stops.
+    port: Option<u16>,
+    unique_port: Arc<Mutex<UniquePort>>,
+    muxer: Option<GRpcServerMuxer>,
+}
+
+impl BrokeredServer {
+    pub fn service_id(&self) -> ServiceId {
+        self.service_id
+    }
+
+    // Whether the server has stopped, e.g. because the plugin shut down, or serving failed.
+    pub fn is_finished(&self) -> bool {
+        self.serving.is_finished()
+    }
+
+    // Stops accepting connections, waits for in-flight calls to finish, up to the grace period,
+    // and removes the server's socket. Its service_id can then be used for another server.
+    // Returns how serving ended, same as wait.
+    pub async fn close(self) -> Result<(), Error> {
+        log::info!("Closing brokered server {}", self.service_id);
+        self.close_trigger.trigger();
+        if let Some(muxer) = &self.muxer {
+            muxer.close_listener(self.service_id);
+        }
+        self.wait().await
+    }
+
+    // Waits for the server to stop, without stopping it, and returns whether it failed.
+    // Its service_id, and its port, can then be used for another server.
+    pub async fn wait(self) -> Result<(), Error> {
+        let result = match self.serving.await {
+            Ok(result) => result,
+            Err(e) => Err(Error::BrokeredServerPanicked(e.to_string())),
+        };
+        if let Err(e) = &result {
+            log::error!("Brokered server {} failed: {}", self.service_id, e);
+        }
+        if let Some(muxer) = &self.muxer {
+            muxer.close_listener(self.service_id);
+        }
+        self.used_ids.lock().unwrap().used.remove(&self.service_id);
+        if let Some(port) = self.port {
+            self.unique_port.lock().await.release(port);
+        }
+        log::info!("Brokered server {} stopped", self.service_id);
+        result
+    }
+}
+
 // Brokers connections by service_id
-// Not necessarily threadsafe, so caller should Arc<RwLock<>> this,
-// but I don't know how you'd get multiple mutable references without that anyway.
+// Clones share the same brokered servers and host services, so each task can have its own.
+#[derive(Clone)]
 pub struct GRpcBroker {
-    unique_port: UniquePort,
-    used_ids: HashSet<u32>,
-    next_id: u32,
+    // Held while binding, so concurrent servers don't pick the same port.
+    unique_port: Arc<Mutex<UniquePort>>,
+    listen_config: ListenConfig,
+    // Released as brokered servers are closed.
+    used_ids: UsedIds,
+
+    // Brokered servers drain along with the main server on shutdown.
+    drain: Drain,
 
-    listener: triggered::Listener,
+    // Brokered servers, and connections to the host's brokered servers, use TLS under AutoMTLS
+    auto_mtls: Option<AutoMtls>,
 
     // Send the client information on new services and their endpoints
     outgoing_conninfo_sender: UnboundedSender<Result<ConnInfo, Status>>,
 
     // Services on the host-side that we've been informed of
-    // The optional ConnInfo entry allows us to park a None
-    // against a ServiceId that was previously used, so it won't get
-    // reused.
-    host_services: Arc<Mutex<HashMap<ServiceId, Option<ConnInfo>>>>,
+    host_services: Arc<HostServices>,
+
+    // Set when the host multiplexes brokered connections over its connection to us.
+    muxer: Option<GRpcServerMuxer>,
+
+    // Our knocks on the host's brokered servers, waiting for the host to answer
+    knock_acks: KnockAcks,
+    // One dial knocks at a time, across every clone.
+    dialing: Arc<Mutex<()>>,
+
+    // Run on every call made by clients from dial_client
+    interceptors: Interceptors,
 }
 
 impl GRpcBroker {
     pub fn new(
         unique_port: UniquePort,
+        listen_config: ListenConfig,
         outgoing_conninfo_sender: UnboundedSender<Result<ConnInfo, Status>>,
         mut incoming_conninfo_stream_receiver_receiver: UnboundedReceiver<Streaming<ConnInfo>>,
-        listener: triggered::Listener,
+        drain: Drain,
+        auto_mtls: Option<AutoMtls>,
+        muxer: Option<GRpcServerMuxer>,
     ) -> Self {
         log::info!("Creating new GrpcBroker");
-        let host_services = Arc::new(Mutex::new(HashMap::new()));
+        let host_services = Arc::new(HostServices::default());
+        let knock_acks: KnockAcks = Arc::new(Mutex::new(HashMap::new()));
 
         log::trace!("spawning a process to receive the stream of incoming ConnInfo's, and then the ConnInfo's themselves from host side...");
         let host_services_for_closure = host_services.clone();
+        let muxer_for_closure = muxer.clone();
+        let knock_acks_for_closure = knock_acks.clone();
+        let outgoing_conninfo_sender_for_closure = outgoing_conninfo_sender.clone();
         tokio::spawn(async move {
             log::trace!(
                 "Inside spawn'd process. Waiting for the stream of ConnInfo's to be available...."
@@ -72,20 +344,32 @@ impl GRpcBroker {
                 }
             };
 
-            Self::blocking_incoming_conn(incoming_conninfo_stream, host_services_for_closure).await
+            Self::blocking_incoming_conn(
+                incoming_conninfo_stream,
+                host_services_for_closure,
+                muxer_for_closure,
+                knock_acks_for_closure,
+                outgoing_conninfo_sender_for_closure,
+            )
+            .await
         });
 
         Self {
-            next_id: 1, // start next id at a number where it won't conflict with other services
-            used_ids: HashSet::new(),
-            unique_port,
+            used_ids: Arc::new(std::sync::Mutex::new(ServiceIds::new())),
+            unique_port: Arc::new(Mutex::new(unique_port)),
+            listen_config,
             outgoing_conninfo_sender,
             host_services,
-            listener,
+            drain,
+            auto_mtls,
+            muxer,
+            knock_acks,
+            dialing: Arc::new(Mutex::new(())),
+            interceptors: Interceptors::default(),
         }
     }
 
-    pub async fn new_grpc_server<S>(&mut self, plugin: S) -> Result<ServiceId, Error>
+    pub async fn new_grpc_server<S>(&self, plugin: S) -> Result<BrokeredServer, Error>
     where
         S: Service<Request<Body>, Response = Response<BoxBody>>
             + NamedService
@@ -98,19 +382,23 @@ impl GRpcBroker {
     {
         log::info!("called");
 
-        // get next service_id, increment the underlying value, and release lock in the block
-        let service_id = self.get_unused_service_id();
+        // get next service_id and reserve it, under the same lock so no other server takes it
+        let service_id = {
+            let mut used_ids = self.used_ids.lock().unwrap();
+            let service_id = used_ids.next_unused();
+            used_ids.used.insert(service_id);
+            service_id
+        };
         log::info!("newServer - obtained an unused service_id: {}", service_id);
 
-        self.new_grpc_server_with_service_id(service_id, plugin)
-            .await
+        self.serve_brokered(service_id, plugin).await
     }
 
     pub async fn new_grpc_server_with_service_id<S>(
-        &mut self,
+        &self,
         service_id: ServiceId,
         plugin: S,
-    ) -> Result<ServiceId, Error>
+    ) -> Result<BrokeredServer, Error>
     where
         S: Service<Request<Body>, Response = Response<BoxBody>>
             + NamedService
@@ -123,174 +411,260 @@ impl GRpcBroker {
     {
         log::info!("called");
 
-        if self.used_ids.contains(&service_id) {
-            return Err(Error::Other(anyhow!("In GrpcBroker, the service_id {} was provided to open a new server with, but it was found to exist already in the used set.", service_id)));
-        }
-
         // reserve current service_id
-        self.used_ids.insert(service_id);
-
-        let temp_socket = TempSocket::new()
-        .with_context(|| format!("newServer({}) Failed to create a new TempSocket for opening a new JSON-RPC 2.0 server", service_id))?;
-        let socket_path = temp_socket.socket_filename()
-            .with_context(|| format!("newServer({}) Failed to get a temporary socket filename from the temp socket for opening a new JSON-RPC 2.0 server", service_id))?;
-        log::info!(
-            "newServer({}) Created a temp socket path: {}",
-            service_id,
-            socket_path
-        );
-
-        let listener = self.listener.clone();
-
-        tokio::spawn(async move {
-            log::debug!(
-                "newServer({}) - spawned into separate task to wait for this server to complete...",
-                service_id
-            );
-
-            let socket_path = temp_socket.socket_filename()
-            .with_context(|| format!("newServer({}) Inside spawned grpc server, failed to get a temporary socket filename from the temp socket for opening a new JSON-RPC 2.0 server", service_id)).unwrap();
+        if !self.used_ids.lock().unwrap().used.insert(service_id) {
+            return Err(Error::ServiceIdInUse(service_id));
+        }
 
-            // create incoming stream from unix socket above...
-            let incoming_stream_from_socket = incoming_from_path(socket_path.as_str()).await
-                .with_context(|| format!("newServer({}) Inside spawned grpc server, unable to open incoming UnixStream from socket {}", service_id, socket_path.as_str())).unwrap();
-            log::trace!("newServer({}) Inside spawned grpc server, created Incoming unix stream from the socket", service_id);
+        self.serve_brokered(service_id, plugin).await
+    }
 
-            log::info!(
-                "newServer({}) Inside spawned grpc server, starting a new grpc service...",
-                service_id
-            );
-            let grpc_service_future = tonic::transport::Server::builder()
-                .add_service(plugin)
-                .serve_with_incoming_shutdown(incoming_stream_from_socket, async {
-                    listener.await
-                });
-
-            if let Err(err) = grpc_service_future.await.with_context(|| {
-                format!(
-                    "newServer({}) Inside spawned grpc server, service future failed",
+    // Serves plugin under a service_id that's already been reserved for it, and releases the
+    // service_id if it can't.
+    async fn serve_brokered<S>(
+        &self,
+        service_id: ServiceId,
+        plugin: S,
+    ) -> Result<BrokeredServer, Error>
+    where
+        S: Service<Request<Body>, Response = Response<BoxBody>>
+            + NamedService
+            + Clone
+            + Send
+            + 'static,
+        <S as Service<http::Request<hyper::Body>>>::Future: Send + 'static,
+        <S as Service<http::Request<hyper::Body>>>::Error:
+            Into<Box<dyn std::error::Error + Send + Sync>> + Send,
+    {
+        // Multiplexed servers are reached by the host knocking on their service_id,
+        // so there's no ConnInfo to tell it about them.
+        let (incoming, conn_info, port) = match &self.muxer {
+            Some(muxer) => {
+                log::info!(
+                    "newServer({}) Listening on the multiplexed connection",
                     service_id
+                );
+                (muxer.listener(service_id), None, None)
+            }
+            None => {
+                // Listen before spawning, since a tcp listener's address is only known once it's bound.
+                let transport::Listener {
+                    network,
+                    address,
+                    incoming,
+                } = match transport::listen(
+                    &self.listen_config,
+                    &mut *self.unique_port.lock().await,
                 )
-            }) {
-                log::error!(
-                    "newServer({}) Inside spawned grpc server, it errored: {}",
+                .await
+                {
+                    Ok(listener) => listener,
+                    Err(e) => {
+                        log::error!(
+                            "newServer({}) Failed to open a listener for a new brokered gRPC server: {}",
+                            service_id,
+                            e
+                        );
+                        self.used_ids.lock().unwrap().used.remove(&service_id);
+                        return Err(e);
+                    }
+                };
+                log::info!(
+                    "newServer({}) Listening on {}:{}",
                     service_id,
-                    err
+                    network,
+                    address
                 );
+
+                let port = match network.as_str() {
+                    "tcp" => address.parse::<SocketAddr>().ok().map(|addr| addr.port()),
+                    _ => None,
+                };
+                let conn_info = ConnInfo {
+                    network,
+                    address,
+                    service_id,
+                    knock: None,
+                };
+                (incoming, Some(conn_info), port)
             }
+        };
 
+        // Stops when it's closed, or when the plugin shuts down.
+        let (close_trigger, close_listener) = triggered::trigger();
+        let shutdown_listener = self.drain.listener();
+        let stop = move || {
+            let closed = close_listener.clone();
+            let shutdown = shutdown_listener.clone();
+            async move {
+                future::select(closed, shutdown).await;
+            }
+        };
+        let incoming_stream = tls::incoming(incoming, self.auto_mtls.clone());
+
+        // The server is already listening, so the host can connect as soon as it hears about it.
+        if let Some(conn_info) = conn_info {
+            if self.outgoing_conninfo_sender.send(Ok(conn_info)).is_err() {
+                log::error!(
+                    "newServer({}) - The host's broker stream has ended, so it can't be told about this server",
+                    service_id
+                );
+                self.used_ids.lock().unwrap().used.remove(&service_id);
+                if let Some(port) = port {
+                    self.unique_port.lock().await.release(port);
+                }
+                return Err(Error::BrokerStreamClosed);
+            }
             log::info!(
-                "newServer({}) Inside spawned grpc server, exiting task. Service has ended.",
+                "newServer({}) - Sent ConnInfo to client-side broker",
                 service_id
             );
-        });
+        }
 
-        log::debug!(
-            "newServer({}) - Creating ConnInfo for this service to send to the client-side broker.",
-            service_id
+        let stop_serving = stop();
+        let serving = self.drain.spawn(
+            format!("Brokered server {}", service_id),
+            async move {
+                log::info!(
+                    "newServer({}) Inside spawned grpc server, starting a new grpc service...",
+                    service_id
+                );
+                let result = tonic::transport::Server::builder()
+                    .add_service(plugin)
+                    .serve_with_incoming_shutdown(incoming_stream, stop_serving)
+                    .await;
+
+                match &result {
+                    Ok(()) => log::info!(
+                        "newServer({}) Inside spawned grpc server, exiting task. Service has ended.",
+                        service_id
+                    ),
+                    Err(err) => log::error!(
+                        "newServer({}) Inside spawned grpc server, it errored: {}",
+                        service_id,
+                        err
+                    ),
+                }
+                Ok(result?)
+            },
+            stop(),
         );
-        let conn_info = ConnInfo {
-            network: "unix".to_string(),
-            address: socket_path,
-            service_id,
-        };
 
-        log::debug!(
-            "newServer({}) - Created ConnInfo for this service: {:?}",
+        Ok(BrokeredServer {
             service_id,
-            conn_info
-        );
-
-        self.outgoing_conninfo_sender
-            .send(Ok(conn_info.clone()))
-            .with_context(|| {
-                format!(
-                    "Failed to send ConnInfo {:?} to the client/host/consumer of this plugin.",
-                    conn_info
-                )
-            })?;
-        log::info!(
-            "newServer({}) - Sent ConnInfo to client-side broker",
-            service_id
-        );
-
-        Ok(service_id)
+            close_trigger,
+            serving,
+            used_ids: self.used_ids.clone(),
+            port,
+            unique_port: self.unique_port.clone(),
+            muxer: self.muxer.clone(),
+        })
     }
 
-    pub fn get_unused_service_id(&mut self) -> u32 {
-        // keep incrementing next_id so long as it has already been used.
-        while self.used_ids.contains(&self.next_id) {
-            self.next_id += 1;
-        }
-
-        // return service_id that is not yet used.
-        self.next_id
+    // The service_id new_grpc_server would use next. It isn't reserved, so another server may
+    // take it first.
+    pub fn get_unused_service_id(&self) -> u32 {
+        self.used_ids.lock().unwrap().next_unused()
     }
 
-    pub fn get_unused_port(&mut self) -> Option<u16> {
-        self.unique_port.get_unused_port()
+    pub async fn get_unused_port(&self) -> Option<u16> {
+        self.unique_port.lock().await.get_unused_port()
     }
 
-    pub async fn dial_to_host_service(&mut self, service_id: ServiceId) -> Result<Channel, Error> {
-        let conn_info = self.get_incoming_conninfo_retry(service_id, 5).await?;
+    // Connects to a server the host brokered, waiting up to DIAL_TIMEOUT for the host to tell us about it.
+    pub async fn dial_to_host_service(&self, service_id: ServiceId) -> Result<Channel, Error> {
+        self.dial_to_host_service_within(service_id, DIAL_TIMEOUT, future::pending())
+            .await
+    }
 
-        let channel = match conn_info.network.as_str() {
-            "tcp" => Endpoint::try_from(conn_info.address)?.connect().await?,
-            "unix" => {
-                // Copied from: https://github.com/hyperium/tonic/blob/master/examples/src/uds/client.rs
-                Endpoint::try_from("http://[::]:50051")?
-                    .connect_with_connector(tower_service_fn(move |_: Uri| {
-                        // Connect to a Uds socket
-                        // The clone ensures this closure doesn't consume the environment.
-                        UnixStream::connect(conn_info.address.clone())
-                    }))
-                    .await?
+    // Same as dial_to_host_service, but waits up to wait for the host to tell us about the service,
+    // and gives up early once cancel resolves, e.g. on a ShutdownToken.
+    pub async fn dial_to_host_service_within<C>(
+        &self,
+        service_id: ServiceId,
+        wait: Duration,
+        cancel: C,
+    ) -> Result<Channel, Error>
+    where
+        C: Future<Output = ()>,
+    {
+        let host = self.host_dialer();
+        tokio::select! {
+            channel = host.dial(service_id, wait) => channel,
+            _ = cancel => {
+                log::debug!("Dialing host service {} was cancelled", service_id);
+                Err(Error::DialCancelled(service_id))
             }
-            s => return Err(Error::NetworkTypeUnknown(s.to_string())),
-        };
+        }
+    }
 
-        Ok(channel)
+    // A broker whose clients from dial_client also run interceptor on every call, e.g. to add
+    // metadata the host expects. Clients this broker already dialed are left as they are.
+    pub fn with_interceptor<F>(mut self, interceptor: F) -> Self
+    where
+        F: Fn(tonic::Request<()>) -> Result<tonic::Request<()>, Status> + Send + Sync + 'static,
+    {
+        self.interceptors = self.interceptors.with(interceptor);
+        self
     }
 
-    #[async_recursion]
-    async fn get_incoming_conninfo_retry(
-        &mut self,
-        service_id: ServiceId,
-        retry_count: usize,
-    ) -> Result<ConnInfo, Error> {
-        match self.get_incoming_conninfo(service_id).await {
-            None => match retry_count {
-                0 => Err(Error::ServiceIdDoesNotExist(service_id)),
-                _c => {
-                    sleep(Duration::from_secs(1)).await;
-                    self.get_incoming_conninfo_retry(service_id, retry_count - 1)
+    // A client for a server the host brokered, dialed the same way as dial_to_host_service.
+    // See the brokered_client macro for making tonic's generated clients dialable.
+    pub async fn dial_client<C>(&self, service_id: ServiceId) -> Result<C, Error>
+    where
+        C: BrokeredClient,
+    {
+        let channel = self.dial_to_host_service(service_id).await?;
+        Ok(C::from_channel(BrokeredChannel::new(
+            channel,
+            self.interceptors.clone(),
+        )))
+    }
+
+    // A Channel to a server the host brokered, which connects when first used, and reconnects
+    // whenever its connection fails, to wherever the host last told us the server is.
+    pub fn dial_to_host_service_lazy(&self, service_id: ServiceId) -> Result<Channel, Error> {
+        let host = self.host_dialer();
+        let channel = Endpoint::try_from("http://[::]:50051")?.connect_with_connector_lazy(
+            tower_service_fn(move |_: Uri| {
+                let host = host.clone();
+                async move {
+                    host.connect(service_id, DIAL_TIMEOUT)
                         .await
+                        .map_err(std::io::Error::other)
                 }
-            },
-            Some(conn_info) => Ok(conn_info),
-        }
+            }),
+        )?;
+
+        Ok(channel)
     }
 
-    //https://github.com/hashicorp/go-plugin/blob/master/grpc_broker.go#L371
-    async fn get_incoming_conninfo(&mut self, service_id: ServiceId) -> Option<ConnInfo> {
-        // hold lock for duration of this function, so we can atomically park a None
-        // in case we pulled a ConnInfo out.
-        let mut hs = self.host_services.lock().await;
+    // Forgets where the host said a brokered server is, e.g. once it's closed, so it's no longer
+    // dialed, and dials wait for the host to tell us about it again.
+    // Returns whether the host had told us about it.
+    pub async fn forget_host_service(&self, service_id: ServiceId) -> bool {
+        log::debug!("Forgetting host service {}", service_id);
+        self.host_services.forget(service_id).await
+    }
 
-        match hs.remove(&service_id) {
-            None | Some(None) => None,
-            Some(Some(conn_info)) => {
-                // if some conn_info existed, replace it with None before exiting
-                hs.insert(service_id, None);
-                Some(conn_info)
-            }
+    fn host_dialer(&self) -> HostDialer {
+        HostDialer {
+            host_services: self.host_services.clone(),
+            muxer: self.muxer.clone(),
+            knock_acks: self.knock_acks.clone(),
+            dialing: self.dialing.clone(),
+            outgoing_conninfo_sender: self.outgoing_conninfo_sender.clone(),
+            auto_mtls: self.auto_mtls.clone(),
         }
     }
 
     // This function will run forever. tokio::spawn this!
     async fn blocking_incoming_conn(
         mut stream: Streaming<ConnInfo>,
-        host_services: Arc<Mutex<HashMap<ServiceId, Option<ConnInfo>>>>,
+        host_services: Arc<HostServices>,
+        muxer: Option<GRpcServerMuxer>,
+        knock_acks: KnockAcks,
+        outgoing_conninfo_sender: UnboundedSender<Result<ConnInfo, Status>>,
     ) {
         log::info!("blocking_incoming_conn - perpetually listening for incoming ConnInfo's",);
         while let Some(conn_info_result) = stream.next().await {
@@ -302,18 +676,56 @@ impl GRpcBroker {
                     );
                     break; //out of the while loop
                 }
+                Ok(ConnInfo {
+                    service_id,
+                    knock: Some(knock),
+                    ..
+                }) if knock.knock => {
+                    if knock.ack || !knock.error.is_empty() {
+                        // The host's answer to one of our knocks
+                        match knock_acks.lock().await.remove(&service_id) {
+                            Some(ack_sender) => {
+                                let _ = ack_sender.send(knock);
+                            }
+                            None => log::warn!(
+                                "Received an answer to a knock on {} that nobody is waiting for",
+                                service_id
+                            ),
+                        }
+                        continue;
+                    }
+
+                    // The host is about to open a stream to one of our brokered servers
+                    log::debug!("Received knock on {}", service_id);
+                    let answer = match &muxer {
+                        Some(muxer) => muxer.accept_knock(service_id),
+                        None => Err(Error::Multiplex(
+                            "the host didn't ask for multiplexing".to_string(),
+                        )),
+                    };
+                    let knock = match answer {
+                        Ok(()) => Knock {
+                            knock: true,
+                            ack: true,
+                            error: String::new(),
+                        },
+                        Err(e) => Knock {
+                            knock: true,
+                            ack: false,
+                            error: e.to_string(),
+                        },
+                    };
+                    if let Err(e) = outgoing_conninfo_sender.send(Ok(ConnInfo {
+                        service_id,
+                        knock: Some(knock),
+                        ..Default::default()
+                    })) {
+                        log::error!("Unable to answer the knock on {}: {}", service_id, e);
+                    }
+                }
                 Ok(conn_info) => {
                     log::info!("Received conn_info: {:?}", conn_info);
-
-                    let mut hs = host_services.lock().await;
-                    log::trace!("Write-locked the host services to add the new ConnInfo",);
-
-                    log::trace!(
-                        "Only creating a new entry if one doesn't exist for this ServiceId: {}",
-                        conn_info.service_id
-                    );
-                    hs.entry(conn_info.service_id)
-                        .or_insert_with(|| Some(conn_info));
+                    host_services.publish(conn_info).await;
                 }
             }
         }
@@ -321,10 +733,59 @@ impl GRpcBroker {
     }
 }
 
+// Connects to the endpoint described by a ConnInfo, over either tcp or a unix socket,
+// and over TLS when AutoMTLS is on.
+pub(crate) async fn dial(
+    conn_info: ConnInfo,
+    auto_mtls: Option<AutoMtls>,
+) -> Result<Channel, Error> {
+    let address = conn_info.address;
+    let channel =
+        match conn_info.network.as_str() {
+            // go-plugin advertises tcp addresses as a bare host:port
+            "tcp" => Endpoint::try_from(format!("http://{}", address))?
+                .connect_with_connector(tower_service_fn(move |_: Uri| {
+                    let address = address.clone();
+                    let auto_mtls = auto_mtls.clone();
+                    async move { connect_io(TcpStream::connect(address).await?, auto_mtls).await }
+                }))
+                .await?,
+            "unix" => {
+                // Copied from: https://github.com/hyperium/tonic/blob/master/examples/src/uds/client.rs
+                Endpoint::try_from("http://[::]:50051")?
+                .connect_with_connector(tower_service_fn(move |_: Uri| {
+                    // Connect to a Uds socket
+                    // The clone ensures this closure doesn't consume the environment.
+                    let address = address.clone();
+                    let auto_mtls = auto_mtls.clone();
+                    async move { connect_io(UnixStream::connect(address).await?, auto_mtls).await }
+                }))
+                .await?
+            }
+            s => return Err(Error::NetworkTypeUnknown(s.to_string())),
+        };
+
+    Ok(channel)
+}
+
+async fn connect_io<IO>(
+    io: IO,
+    auto_mtls: Option<AutoMtls>,
+) -> Result<tls::MaybeTlsStream<IO>, std::io::Error>
+where
+    IO: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    match auto_mtls {
+        None => Ok(tls::MaybeTlsStream::Plain(io)),
+        Some(auto_mtls) => auto_mtls.connect(io).await,
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::unique_port;
+    use assert_matches::assert_matches;
     use tokio::sync::mpsc::unbounded_channel;
 
     #[tokio::test]
@@ -332,22 +793,132 @@ mod test {
         let (_t, l) = triggered::trigger();
         let (t1, _r1) = unbounded_channel::<Result<ConnInfo, Status>>();
         let (_t2, r2) = unbounded_channel::<Streaming<ConnInfo>>();
-        let mut g = GRpcBroker::new(unique_port::UniquePort::new(), t1, r2, l);
+        let g = GRpcBroker::new(
+            unique_port::UniquePort::new(),
+            ListenConfig::default(),
+            t1,
+            r2,
+            Drain::new(l),
+            None,
+            None,
+        );
 
-        g.used_ids.insert(5);
+        g.used_ids.lock().unwrap().used.insert(5);
 
         assert_eq!(1, g.get_unused_service_id());
         // still unuused
         assert_eq!(1, g.get_unused_service_id());
-        g.used_ids.insert(1);
+        g.used_ids.lock().unwrap().used.insert(1);
         assert_eq!(2, g.get_unused_service_id());
-        g.used_ids.insert(2);
+        g.used_ids.lock().unwrap().used.insert(2);
         assert_eq!(3, g.get_unused_service_id());
-        g.used_ids.insert(3);
+        g.used_ids.lock().unwrap().used.insert(3);
         assert_eq!(4, g.get_unused_service_id());
-        g.used_ids.insert(4);
+        g.used_ids.lock().unwrap().used.insert(4);
 
         // skip 5 which was pre-inserted
         assert_eq!(6, g.get_unused_service_id());
     }
+
+    #[tokio::test]
+    async fn test_concurrent_servers() {
+        let (_t, l) = triggered::trigger();
+        let (t1, _r1) = unbounded_channel::<Result<ConnInfo, Status>>();
+        let (_t2, r2) = unbounded_channel::<Streaming<ConnInfo>>();
+        let g = GRpcBroker::new(
+            unique_port::UniquePort::new(),
+            ListenConfig::default(),
+            t1,
+            r2,
+            Drain::new(l),
+            None,
+            None,
+        );
+
+        fn shareable<T: Clone + Send + Sync>(_: &T) {}
+        shareable(&g);
+
+        // Every task gets its own broker, and its own service_id.
+        let (_, health_service) = tonic_health::server::health_reporter();
+        let servers: Vec<_> = (0..8)
+            .map(|_| {
+                let g = g.clone();
+                let health_service = health_service.clone();
+                tokio::spawn(async move { g.new_grpc_server(health_service).await.unwrap() })
+            })
+            .collect();
+        let mut service_ids = HashSet::new();
+        for server in servers {
+            assert!(service_ids.insert(server.await.unwrap().service_id()));
+        }
+        assert_eq!(service_ids.len(), 8);
+    }
+
+    #[tokio::test]
+    async fn test_close_auto_mtls_server() {
+        let (host_cert, _) = tls::generate_cert().unwrap();
+        let auto_mtls = AutoMtls::new(&host_cert.to_pem().unwrap()).unwrap();
+        let (_t, l) = triggered::trigger();
+        let (t1, mut r1) = unbounded_channel::<Result<ConnInfo, Status>>();
+        let (_t2, r2) = unbounded_channel::<Streaming<ConnInfo>>();
+        let g = GRpcBroker::new(
+            unique_port::UniquePort::new(),
+            ListenConfig::default(),
+            t1,
+            r2,
+            Drain::new(l),
+            Some(auto_mtls),
+            None,
+        );
+
+        let (_, health_service) = tonic_health::server::health_reporter();
+        let server = g.new_grpc_server(health_service).await.unwrap();
+        let conn_info = r1.recv().await.unwrap().unwrap();
+        assert!(UnixStream::connect(&conn_info.address).await.is_ok());
+
+        // Closing it stops listening, even though TLS handshakes happen off the server's task.
+        server.close().await.unwrap();
+        assert!(!std::path::Path::new(&conn_info.address).exists());
+        assert!(UnixStream::connect(&conn_info.address).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_close_releases_port() {
+        let (host_cert, _) = tls::generate_cert().unwrap();
+        let auto_mtls = AutoMtls::new(&host_cert.to_pem().unwrap()).unwrap();
+        // Under AutoMTLS, the listener is only let go along with the stream of handshakes.
+        for auto_mtls in [None, Some(auto_mtls)] {
+            // A range of one port, which only one server can have at a time.
+            let port = portpicker::pick_unused_port().unwrap();
+            let (_t, l) = triggered::trigger();
+            let (t1, _r1) = unbounded_channel::<Result<ConnInfo, Status>>();
+            let (_t2, r2) = unbounded_channel::<Streaming<ConnInfo>>();
+            let g = GRpcBroker::new(
+                unique_port::UniquePort::with_range(port, port).unwrap(),
+                ListenConfig {
+                    transport: transport::Transport::Tcp,
+                    ..Default::default()
+                },
+                t1,
+                r2,
+                Drain::new(l),
+                auto_mtls,
+                None,
+            );
+
+            let (_, health_service) = tonic_health::server::health_reporter();
+            for _ in 0..3 {
+                let server = g.new_grpc_server(health_service.clone()).await.unwrap();
+                assert_matches!(
+                    g.new_grpc_server(health_service.clone()).await.err(),
+                    Some(Error::NoTCPPortAvailable)
+                );
+                // Let it start accepting before it's closed.
+                assert!(TcpStream::connect(("127.0.0.1", port)).await.is_ok());
+                tokio::task::yield_now().await;
+                server.close().await.unwrap();
+                assert!(TcpStream::connect(("127.0.0.1", port)).await.is_err());
+            }
+        }
+    }
 }
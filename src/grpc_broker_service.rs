@@ -6,7 +6,6 @@ pub mod grpc_plugins {
 pub use grpc_plugins::ConnInfo;
 
 use super::error::{into_status, Error};
-use anyhow::Result;
 use async_stream::stream;
 use futures::stream::Stream;
 use grpc_plugins::grpc_broker_server::{GrpcBroker, GrpcBrokerServer};
@@ -20,11 +19,16 @@ use tonic::{async_trait, Request, Response, Status, Streaming};
 pub async fn new_server(
     conn_info_receiver: UnboundedReceiver<Result<ConnInfo, Status>>,
     incoming_conninfo_stream_sender: UnboundedSender<Streaming<ConnInfo>>,
+    listener: triggered::Listener,
 ) -> Result<GrpcBrokerServer<GrpcBrokerService>, Error> {
     log::trace!("new_server - called.");
 
     log::trace!("new_server - creating GrpcBrokerImpl.");
-    let broker = GrpcBrokerService::new(conn_info_receiver, incoming_conninfo_stream_sender)?;
+    let broker = GrpcBrokerService::new(
+        conn_info_receiver,
+        incoming_conninfo_stream_sender,
+        listener,
+    )?;
 
     log::trace!("new_server - Returning a new broker as well as a Sender to send ConnInfo to the Plugin Client.");
     Ok(GrpcBrokerServer::new(broker))
@@ -49,11 +53,12 @@ impl GrpcBrokerService {
     pub fn new(
         conn_info_receiver: UnboundedReceiver<Result<ConnInfo, Status>>,
         incoming_conninfo_stream_sender: UnboundedSender<Streaming<ConnInfo>>,
+        listener: triggered::Listener,
     ) -> Result<GrpcBrokerService, Error> {
         log::trace!("called.");
 
         log::trace!("creating outgoing stream.");
-        let outgoing_stream = Self::new_outgoing_stream(conn_info_receiver);
+        let outgoing_stream = Self::new_outgoing_stream(conn_info_receiver, listener);
 
         // we use a channel to provide one-way send between the constructor where we have this outgoing stream,
         // and a gRPC method stream_start where it will be consumed.
@@ -73,8 +78,10 @@ impl GrpcBrokerService {
         })
     }
 
+    // Ends when we start shutting down, so the host's stream doesn't hold up draining.
     fn new_outgoing_stream(
         mut conn_info_receiver: UnboundedReceiver<Result<ConnInfo, Status>>,
+        listener: triggered::Listener,
     ) -> <Self as GrpcBroker>::StartStreamStream {
         log::trace!("new_outgoing_stream called.");
 
@@ -82,7 +89,14 @@ impl GrpcBrokerService {
             log::trace!("outgoing_stream repeater initialized.");
             loop {
                 log::trace!("outgoing_stream loop iteration");
-                match conn_info_receiver.recv().await {
+                let received = tokio::select! {
+                    received = conn_info_receiver.recv() => received,
+                    _ = listener.clone() => {
+                        log::info!("Ending the outgoing ConnInfo stream, since we're shutting down.");
+                        break;
+                    }
+                };
+                match received {
                     Some(result) => {
                         log::trace!("Sending Result<ConnInfo> to outgoing_stream: {:?}.", result);
                         yield result
@@ -121,9 +135,8 @@ impl GrpcBroker for GrpcBrokerService {
 
         match interior.outgoing_conninfo_receiver_receiver.recv().await {
             None => {
-                let errmsg = "outgoing_stream was None, which, being initalized in the constructor, was vended off already. Was this method called twice? Did someone else .take() it?";
-                log::error!("{}", errmsg);
-                Err(Status::unknown(errmsg))
+                log::error!("The host started the broker stream more than once.");
+                Err(into_status(Error::BrokerStreamAlreadyStarted))
             }
             Some(os) => {
                 log::trace!("sending the Stream of incoming ConnInfo to someone else to broker...");
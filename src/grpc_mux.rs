@@ -0,0 +1,244 @@
+// go-plugin 1.5+ can multiplex brokered connections over the one connection the host makes
+// to the plugin, instead of opening a listener per brokered server. The connection runs a yamux
+// session. Before opening a stream to a brokered server, the dialer "knocks" on its service_id
+// through the GRPCBroker stream, and the other side routes the next stream to that server.
+// Copied from: https://github.com/hashicorp/go-plugin/blob/master/internal/grpcmux/grpc_server_muxer.go
+use super::error::Error;
+use super::transport::Connection;
+use super::ServiceId;
+use async_stream::stream;
+use futures::stream::{BoxStream, StreamExt};
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::sync::watch;
+use tokio::time::timeout;
+use tokio_util::compat::{FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+
+// The host asks for multiplexing through this variable.
+pub const ENV_PLUGIN_MULTIPLEX_GRPC: &str = "PLUGIN_MULTIPLEX_GRPC";
+
+// How long to wait for the host's connection before giving up on dialing over it. Same as go-plugin.
+const SESSION_TIMEOUT: Duration = Duration::from_secs(5);
+
+type Incoming = BoxStream<'static, Result<Connection, std::io::Error>>;
+type ConnSender = UnboundedSender<Result<Connection, std::io::Error>>;
+
+// Parses the variable the way go's strconv.ParseBool would.
+pub fn multiplex_from_env() -> bool {
+    matches!(
+        env::var(ENV_PLUGIN_MULTIPLEX_GRPC).as_deref(),
+        Ok("1" | "t" | "T" | "TRUE" | "true" | "True")
+    )
+}
+
+#[derive(Clone)]
+pub struct GRpcServerMuxer {
+    // Available once the host has connected.
+    control: watch::Receiver<Option<yamux::Control>>,
+    control_sender: Arc<watch::Sender<Option<yamux::Control>>>,
+
+    // service_ids the host knocked on, oldest first. Each one claims the next stream the host opens.
+    knocks: Arc<Mutex<VecDeque<ServiceId>>>,
+
+    // Brokered servers waiting for streams
+    listeners: Arc<Mutex<HashMap<ServiceId, ConnSender>>>,
+}
+
+impl GRpcServerMuxer {
+    pub fn new() -> Self {
+        let (control_sender, control) = watch::channel(None);
+        Self {
+            control,
+            control_sender: Arc::new(control_sender),
+            knocks: Arc::new(Mutex::new(VecDeque::new())),
+            listeners: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // Accepts the host's connection from the main listener and runs the yamux session over it.
+    // Streams the host opens without knocking first are for the main gRPC server, and are returned here.
+    pub fn serve(&self, mut incoming: Incoming) -> Incoming {
+        let (default_sender, mut default_receiver) = unbounded_channel();
+        let muxer = self.clone();
+
+        tokio::spawn(async move {
+            log::debug!("accepting initial connection");
+            let conn = match incoming.next().await {
+                Some(Ok(conn)) => conn,
+                Some(Err(e)) => {
+                    log::error!("failed to accept the initial connection: {}", e);
+                    let _ = default_sender.send(Err(e));
+                    return;
+                }
+                None => return,
+            };
+
+            log::debug!("initial server connection accepted");
+            let session = yamux::Connection::new(
+                conn.compat(),
+                yamux::Config::default(),
+                yamux::Mode::Server,
+            );
+            muxer.control_sender.send_replace(Some(session.control()));
+
+            let mut streams = Box::pin(yamux::into_stream(session));
+            while let Some(stream) = streams.next().await {
+                let conn = stream
+                    .map(|st| Connection::Mux(st.compat()))
+                    .map_err(std::io::Error::other);
+
+                let knocked = muxer.knocks.lock().unwrap().pop_front();
+                match knocked {
+                    None => {
+                        log::debug!("sending conn to default listener");
+                        if default_sender.send(conn).is_err() {
+                            log::debug!("default listener is gone");
+                        }
+                    }
+                    Some(service_id) => {
+                        log::debug!("sending conn to brokered listener {}", service_id);
+                        let sent = match muxer.listeners.lock().unwrap().get(&service_id) {
+                            Some(listener) => listener.send(conn).is_ok(),
+                            None => false,
+                        };
+                        if !sent {
+                            log::error!(
+                                "received knock on ID {} that doesn't have a listener",
+                                service_id
+                            );
+                        }
+                    }
+                }
+            }
+
+            log::info!("yamux session with the host ended");
+        });
+
+        stream! {
+            while let Some(conn) = default_receiver.recv().await {
+                yield conn;
+            }
+        }
+        .boxed()
+    }
+
+    // The incoming streams for the brokered server with this service_id.
+    pub fn listener(&self, service_id: ServiceId) -> Incoming {
+        let (sender, mut receiver) = unbounded_channel();
+        self.listeners.lock().unwrap().insert(service_id, sender);
+
+        stream! {
+            while let Some(conn) = receiver.recv().await {
+                yield conn;
+            }
+        }
+        .boxed()
+    }
+
+    // Stops routing streams to the brokered server with this service_id, which ends its listener.
+    pub fn close_listener(&self, service_id: ServiceId) {
+        self.listeners.lock().unwrap().remove(&service_id);
+    }
+
+    // The host knocked on service_id, so the next stream it opens goes to that brokered server.
+    pub fn accept_knock(&self, service_id: ServiceId) -> Result<(), Error> {
+        if !self.listeners.lock().unwrap().contains_key(&service_id) {
+            return Err(Error::Multiplex(format!(
+                "no listener for id {}",
+                service_id
+            )));
+        }
+
+        self.knocks.lock().unwrap().push_back(service_id);
+        Ok(())
+    }
+
+    // Opens a stream to the host, for a connection to one of its brokered servers.
+    pub async fn dial(&self) -> Result<Connection, std::io::Error> {
+        let mut control = self.control.clone();
+        let mut control = match timeout(SESSION_TIMEOUT, control.wait_for(Option::is_some)).await {
+            Ok(Ok(control)) => control.clone().unwrap(),
+            Ok(Err(_)) | Err(_) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "timed out waiting for the host's connection to be established",
+                ))
+            }
+        };
+
+        let stream = control.open_stream().await.map_err(std::io::Error::other)?;
+        Ok(Connection::Mux(stream.compat()))
+    }
+}
+
+impl Default for GRpcServerMuxer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::unix;
+    use futures::stream;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // Opens a stream to the plugin as the host would, and sends it a message.
+    async fn send(control: &mut yamux::Control, message: &[u8]) {
+        let mut stream = control.open_stream().await.unwrap().compat();
+        stream.write_all(message).await.unwrap();
+        stream.flush().await.unwrap();
+        // Keep the stream open, so it's only closed once it's been read.
+        tokio::spawn(async move {
+            let _ = stream.read(&mut [0u8; 1]).await;
+        });
+    }
+
+    async fn receive(incoming: &mut Incoming, len: usize) -> Vec<u8> {
+        let mut conn = incoming.next().await.unwrap().unwrap();
+        let mut message = vec![0u8; len];
+        conn.read_exact(&mut message).await.unwrap();
+        message
+    }
+
+    #[tokio::test]
+    async fn test_knocks_route_streams() {
+        let (plugin, host) = tokio::net::UnixStream::pair().unwrap();
+        let muxer = GRpcServerMuxer::new();
+        let mut main = muxer.serve(
+            stream::once(async move { Ok(Connection::Unix(unix::UnixStream(plugin))) }).boxed(),
+        );
+        let mut first = muxer.listener(1);
+        let mut second = muxer.listener(2);
+
+        let session =
+            yamux::Connection::new(host.compat(), yamux::Config::default(), yamux::Mode::Client);
+        let mut control = session.control();
+        tokio::spawn(async move {
+            let mut streams = Box::pin(yamux::into_stream(session));
+            while streams.next().await.is_some() {}
+        });
+
+        // Streams without a knock go to the main server.
+        send(&mut control, b"main").await;
+        assert_eq!(receive(&mut main, 4).await, b"main");
+
+        // A knock claims the next stream for the server it names.
+        muxer.accept_knock(2).unwrap();
+        send(&mut control, b"second").await;
+        assert_eq!(receive(&mut second, 6).await, b"second");
+        muxer.accept_knock(1).unwrap();
+        send(&mut control, b"first").await;
+        assert_eq!(receive(&mut first, 5).await, b"first");
+
+        // Knocks on servers that aren't listening are refused.
+        assert!(muxer.accept_knock(3).is_err());
+        muxer.close_listener(1);
+        assert!(muxer.accept_knock(1).is_err());
+        assert!(first.next().await.is_none());
+    }
+}
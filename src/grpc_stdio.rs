@@ -3,84 +3,173 @@ pub mod grpc_plugins {
     tonic::include_proto!("plugin");
 }
 
-use super::error::into_status;
-use anyhow::{Context, Result};
+use super::error::Error;
 use async_stream::stream;
 use futures::stream::Stream;
-use gag::BufferRedirect;
 use grpc_plugins::grpc_stdio_server::{GrpcStdio, GrpcStdioServer};
 use grpc_plugins::stdio_data::Channel;
 use grpc_plugins::StdioData;
-use std::io::Read;
+use nix::unistd::{dup, dup2, pipe};
+use std::fs::File;
+use std::io::Write;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
 use std::pin::Pin;
-use tokio::time::{sleep, Duration};
-use tokio_stream::StreamExt;
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncReadExt;
+use tokio::net::unix::pipe::Receiver;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 use tonic::{async_trait, Request, Response, Status};
 
-const CONSOLE_POLL_SLEEP_MILLIS: u64 = 500;
+// How much output is read from a pipe at a time. Same as go-plugin.
+const STDIO_BUFFER_SIZE: usize = 1024;
 
-pub fn new_server() -> GrpcStdioServer<GrpcStdioImpl> {
-    GrpcStdioServer::new(GrpcStdioImpl {})
+// The output to stream to the host, from when it was captured on. It can be streamed once.
+pub type StdioOutput = Arc<Mutex<Option<UnboundedReceiver<StdioData>>>>;
+
+pub fn new_server(
+    output: StdioOutput,
+    listener: triggered::Listener,
+) -> GrpcStdioServer<GrpcStdioImpl> {
+    GrpcStdioServer::new(GrpcStdioImpl { output, listener })
 }
 
-#[derive(Clone)]
-pub struct GrpcStdioImpl {}
+// Where the output streamed to the host comes from
+#[derive(Clone, Default)]
+pub enum StdioSource {
+    // The process's stdout and stderr, redirected
+    #[default]
+    Process,
+    // Whatever's written to StdioWriters, so tests don't touch the real stdout.
+    Writers(StdioOutput),
+    // Nothing. The process's stdout and stderr are left alone.
+    Inherit,
+}
 
-impl GrpcStdioImpl {
-    fn new_combined_stream() -> Result<<Self as GrpcStdio>::StreamStdioStream, Status> {
-        log::trace!("new_inner_stream called. Asked for a stream of stdout and stderr");
-        log::info!("Gagging stdout and stderr to a buffer for redirection to plugin's host.",);
+impl StdioSource {
+    // A source for in-process tests, with writers for stdout and stderr.
+    pub fn writers() -> (Self, StdioWriter, StdioWriter) {
+        let (sender, receiver) = unbounded_channel();
+        let stdout = StdioWriter {
+            channel: Channel::Stdout,
+            sender: sender.clone(),
+        };
+        let stderr = StdioWriter {
+            channel: Channel::Stderr,
+            sender,
+        };
+        (
+            StdioSource::Writers(Arc::new(Mutex::new(Some(receiver)))),
+            stdout,
+            stderr,
+        )
+    }
 
-        let stdoutbuf = BufferRedirect::stdout()
-            .context("Failed to create a BufferRedirec from stdout")
-            .map_err(|e| e.into())
-            .map_err(into_status)?;
-        let stdout_stream = GrpcStdioImpl::new_stream("stdout", Channel::Stdout as i32, stdoutbuf);
+    // Starts capturing output for the host, so none is lost before it asks for it. The process's
+    // stdout and stderr are redirected to pipes from here on, so the original stdout, where the
+    // host expects the handshake, is returned along with the output.
+    pub(crate) fn capture(&self) -> Result<(StdioOutput, Option<File>), Error> {
+        match self {
+            StdioSource::Writers(output) => Ok((output.clone(), None)),
+            StdioSource::Inherit => {
+                let (_, receiver) = unbounded_channel();
+                Ok((Arc::new(Mutex::new(Some(receiver))), None))
+            }
+            StdioSource::Process => {
+                log::info!(
+                    "Redirecting stdout and stderr to pipes, for streaming to the plugin's host."
+                );
+                let (sender, receiver) = unbounded_channel();
 
-        let stderrbuf = BufferRedirect::stderr()
-            .context("Failed to create a BufferRedirec from stderr")
-            .map_err(|e| e.into())
-            .map_err(into_status)?;
-        let stderr_stream = GrpcStdioImpl::new_stream("stderr", Channel::Stderr as i32, stderrbuf);
+                std::io::stdout().flush()?;
+                let stdout = dup(std::io::stdout().as_raw_fd()).map_err(std::io::Error::from)?;
+                // Safe since dup just opened it, and nothing else owns it.
+                let stdout = unsafe { File::from_raw_fd(stdout) };
 
-        let merged_stream = stdout_stream.merge(stderr_stream);
+                redirect(
+                    std::io::stdout().as_raw_fd(),
+                    Channel::Stdout,
+                    sender.clone(),
+                )?;
+                redirect(std::io::stderr().as_raw_fd(), Channel::Stderr, sender)?;
 
-        Ok(Box::pin(merged_stream))
+                Ok((Arc::new(Mutex::new(Some(receiver))), Some(stdout)))
+            }
+        }
     }
+}
 
-    fn new_stream(
-        stream_name: &'static str,
-        channel: i32,
-        mut redirected_buf: BufferRedirect,
-    ) -> impl Stream<Item = Result<StdioData, Status>> {
-        stream! {
-            loop {
-                log::trace!("beginning next iteration of {} reading and streaming...", stream_name);
-                let mut readbuf = String::new();
-                match redirected_buf.read_to_string(&mut readbuf) {
-                    Ok(len) => match len{
-                        0 => {
-                            log::trace!("{} had zero bytes. Sleeping to avoid polling...", stream_name);
-                            sleep(Duration::from_millis(CONSOLE_POLL_SLEEP_MILLIS)).await;
-                        },
-                        _ => {
-                            log::trace!("Sending {} {} bytes of data: {}", stream_name, len, readbuf);
-                            yield Ok(StdioData{
-                                channel,
-                                data: readbuf.into_bytes(),
-                            });
-                        },
-                    },
-                    Err(e) => {
-                        log::error!("Error reading {} data: {:?}", stream_name, e);
-                        yield Err(Status::unknown(format!("Error reading from Stderr of plugin's process: {:?}", e)));
-                    },
-                }
+// Points fd at a new pipe, and sends what's read from the pipe as it arrives.
+fn redirect(fd: RawFd, channel: Channel, sender: UnboundedSender<StdioData>) -> Result<(), Error> {
+    let (reader, writer) = pipe().map_err(std::io::Error::from)?;
+    dup2(writer.as_raw_fd(), fd).map_err(std::io::Error::from)?;
+    drop(writer);
+
+    tokio::spawn(forward_pipe(reader, channel, sender));
+    Ok(())
+}
+
+// Logging here would write to the very pipe being read, so errors are only sent on.
+async fn forward_pipe(reader: OwnedFd, channel: Channel, sender: UnboundedSender<StdioData>) {
+    let mut reader = match Receiver::from_owned_fd(reader) {
+        Ok(reader) => reader,
+        Err(e) => {
+            let _ = sender.send(StdioData {
+                channel: Channel::Stderr as i32,
+                data: format!("Unable to read the plugin's {:?}: {}\n", channel, e).into_bytes(),
+            });
+            return;
+        }
+    };
+
+    let mut buf = vec![0u8; STDIO_BUFFER_SIZE];
+    loop {
+        match reader.read(&mut buf).await {
+            Ok(0) => break,
+            // Once nothing's listening, output is still read, and dropped, so the pipe is never
+            // left full or without a reader while the plugin writes to it.
+            Ok(_) if sender.is_closed() => {}
+            Ok(len) => {
+                let _ = sender.send(StdioData {
+                    channel: channel as i32,
+                    data: buf[..len].to_vec(),
+                });
             }
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(_) => break,
         }
     }
 }
 
+// Stands in for stdout or stderr in tests. Each write is sent to the host as one StdioData.
+#[derive(Clone)]
+pub struct StdioWriter {
+    channel: Channel,
+    sender: UnboundedSender<StdioData>,
+}
+
+impl Write for StdioWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.sender
+            .send(StdioData {
+                channel: self.channel as i32,
+                data: buf.to_vec(),
+            })
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct GrpcStdioImpl {
+    output: StdioOutput,
+    // The stream ends when we start shutting down, so the host's stream doesn't hold up draining.
+    listener: triggered::Listener,
+}
+
 #[async_trait]
 impl GrpcStdio for GrpcStdioImpl {
     type StreamStdioStream =
@@ -92,10 +181,71 @@ impl GrpcStdio for GrpcStdioImpl {
     ) -> Result<Response<Self::StreamStdioStream>, Status> {
         log::trace!("stream_stdio called.");
 
-        let s = GrpcStdioImpl::new_combined_stream()?;
+        let mut output = self
+            .output
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| Status::failed_precondition("stdio is already being streamed"))?;
+        let listener = self.listener.clone();
+        let s = Box::pin(stream! {
+            loop {
+                let data = tokio::select! {
+                    data = output.recv() => data,
+                    _ = listener.clone() => break,
+                };
+                match data {
+                    Some(data) => yield Ok(data),
+                    None => break,
+                }
+            }
+
+            // Whatever was written before shutting down still goes to the host.
+            while let Ok(data) = output.try_recv() {
+                yield Ok(data);
+            }
+        });
 
         log::trace!("stream_stdio responding with a stream of StdioData.",);
 
         Ok(Response::new(s))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_redirect() {
+        // Stands in for stdout, which other tests are writing to.
+        let mut stdout = std::fs::OpenOptions::new()
+            .write(true)
+            .open("/dev/null")
+            .unwrap();
+        let (sender, mut receiver) = unbounded_channel();
+        redirect(stdout.as_raw_fd(), Channel::Stdout, sender).unwrap();
+
+        stdout.write_all(b"hello").unwrap();
+        let data = receiver.recv().await.unwrap();
+        assert_eq!(data.channel, Channel::Stdout as i32);
+        assert_eq!(data.data, b"hello");
+
+        // Output ends once nothing can write to the pipe any more.
+        drop(stdout);
+        assert!(receiver.recv().await.is_none());
+
+        // Writes still succeed once nothing's listening.
+        let (sender, receiver) = unbounded_channel();
+        let mut stderr = std::fs::OpenOptions::new()
+            .write(true)
+            .open("/dev/null")
+            .unwrap();
+        redirect(stderr.as_raw_fd(), Channel::Stderr, sender).unwrap();
+        drop(receiver);
+        for _ in 0..2 {
+            stderr.write_all(b"hello").unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+    }
+}
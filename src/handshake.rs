@@ -0,0 +1,220 @@
+// The handshake line printed by a plugin on stdout, and parsed by the host.
+// https://github.com/hashicorp/go-plugin/blob/master/docs/guide-plugin-write-non-go.md#4-output-handshake-information
+use super::error::Error;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+// The constants are for generating the go-plugin string
+// https://github.com/hashicorp/go-plugin/blob/master/docs/guide-plugin-write-non-go.md
+pub const GRPC_CORE_PROTOCOL_VERSION: usize = 1;
+
+// The host tells the plugin which app protocol versions it speaks through this variable.
+pub const ENV_PLUGIN_PROTOCOL_VERSIONS: &str = "PLUGIN_PROTOCOL_VERSIONS";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Handshake {
+    pub core_protocol_version: usize,
+    pub app_protocol_version: u32,
+    pub network: String,
+    pub address: String,
+    pub protocol: String,
+    // base64 DER of the plugin's certificate under AutoMTLS
+    pub server_cert: Option<String>,
+    // Whether brokered connections are multiplexed over the host's connection. Printed as
+    // a 7th field only when set, since older hosts don't expect it.
+    pub multiplex_grpc: bool,
+}
+
+impl fmt::Display for Handshake {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}|{}|{}|{}|{}|{}",
+            self.core_protocol_version,
+            self.app_protocol_version,
+            self.network,
+            self.address,
+            self.protocol,
+            self.server_cert.as_deref().unwrap_or_default(),
+        )?;
+        if self.multiplex_grpc {
+            write!(f, "|true")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Handshake {
+    type Err = Error;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = line.trim().split('|').collect();
+        if parts.len() < 4 {
+            return Err(Error::InvalidHandshake(format!(
+                "expected at least 4 '|'-separated fields, got {}: {}",
+                parts.len(),
+                line
+            )));
+        }
+
+        let core_protocol_version = parts[0].parse().map_err(|e| {
+            Error::InvalidHandshake(format!(
+                "core protocol version {} is not a number: {}",
+                parts[0], e
+            ))
+        })?;
+        let app_protocol_version = parts[1].parse().map_err(|e| {
+            Error::InvalidHandshake(format!(
+                "app protocol version {} is not a number: {}",
+                parts[1], e
+            ))
+        })?;
+
+        // Older plugins don't print the protocol, and go-plugin treats those as netrpc.
+        let protocol = match parts.get(4) {
+            Some(p) if !p.is_empty() => p.to_string(),
+            _ => "netrpc".to_string(),
+        };
+
+        let server_cert = match parts.get(5) {
+            Some(c) if !c.is_empty() => Some(c.to_string()),
+            _ => None,
+        };
+
+        let multiplex_grpc = match parts.get(6) {
+            Some(m) if !m.is_empty() => m.parse().map_err(|e| {
+                Error::InvalidHandshake(format!("multiplex setting {} is not a boolean: {}", m, e))
+            })?,
+            _ => false,
+        };
+
+        Ok(Handshake {
+            core_protocol_version,
+            app_protocol_version,
+            network: parts[2].to_string(),
+            address: parts[3].to_string(),
+            protocol,
+            server_cert,
+            multiplex_grpc,
+        })
+    }
+}
+
+// What a plugin serving in debug mode prints instead of the handshake, so a host can attach to
+// it, like go-plugin's ReattachConfig (e.g. in TF_REATTACH_PROVIDERS).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ReattachConfig {
+    pub protocol: String,
+    pub protocol_version: u32,
+    pub pid: u32,
+    // Tells the host it didn't launch the plugin, so it shouldn't kill it.
+    pub test: bool,
+    pub addr: ReattachAddr,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ReattachAddr {
+    pub network: String,
+    pub string: String,
+}
+
+impl ReattachConfig {
+    // For this process, serving what the handshake describes.
+    pub fn new(handshake: &Handshake) -> Self {
+        Self {
+            protocol: handshake.protocol.clone(),
+            protocol_version: handshake.app_protocol_version,
+            pid: std::process::id(),
+            test: true,
+            addr: ReattachAddr {
+                network: handshake.network.clone(),
+                string: handshake.address.clone(),
+            },
+        }
+    }
+}
+
+impl fmt::Display for ReattachConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let json = serde_json::to_string(self).map_err(|_| fmt::Error)?;
+        write!(f, "{}", json)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert_matches::assert_matches;
+
+    #[test]
+    fn test_handshake_roundtrip() {
+        let h = Handshake {
+            core_protocol_version: GRPC_CORE_PROTOCOL_VERSION,
+            app_protocol_version: 3,
+            network: "unix".to_string(),
+            address: "/tmp/plugin.sock".to_string(),
+            protocol: "grpc".to_string(),
+            server_cert: None,
+            multiplex_grpc: false,
+        };
+
+        assert_eq!("1|3|unix|/tmp/plugin.sock|grpc|", h.to_string());
+        assert_eq!(h, h.to_string().parse().unwrap());
+
+        let h = Handshake {
+            server_cert: Some("MIIBkTCB+wIJAL".to_string()),
+            ..h
+        };
+        assert_eq!(
+            "1|3|unix|/tmp/plugin.sock|grpc|MIIBkTCB+wIJAL",
+            h.to_string()
+        );
+        assert_eq!(h, h.to_string().parse().unwrap());
+
+        let h = Handshake {
+            multiplex_grpc: true,
+            ..h
+        };
+        assert_eq!(
+            "1|3|unix|/tmp/plugin.sock|grpc|MIIBkTCB+wIJAL|true",
+            h.to_string()
+        );
+        assert_eq!(h, h.to_string().parse().unwrap());
+    }
+
+    #[test]
+    fn test_reattach_config() {
+        let h: Handshake = "1|5|unix|/tmp/plugin.sock|grpc".parse().unwrap();
+        let reattach = ReattachConfig {
+            pid: 1234,
+            ..ReattachConfig::new(&h)
+        };
+        assert_eq!(
+            r#"{"Protocol":"grpc","ProtocolVersion":5,"Pid":1234,"Test":true,"Addr":{"Network":"unix","String":"/tmp/plugin.sock"}}"#,
+            reattach.to_string()
+        );
+    }
+
+    #[test]
+    fn test_handshake_parse() {
+        let h: Handshake = "1|2|tcp|127.0.0.1:1234|grpc\n".parse().unwrap();
+        assert_eq!("tcp", h.network);
+        assert_eq!("127.0.0.1:1234", h.address);
+        assert_eq!("grpc", h.protocol);
+
+        let h: Handshake = "1|2|tcp|127.0.0.1:1234".parse().unwrap();
+        assert_eq!("netrpc", h.protocol);
+
+        assert_matches!(
+            "1|2|tcp".parse::<Handshake>(),
+            Err(Error::InvalidHandshake(_))
+        );
+        assert_matches!(
+            "one|2|tcp|127.0.0.1:1234|grpc".parse::<Handshake>(),
+            Err(Error::InvalidHandshake(_))
+        );
+    }
+}
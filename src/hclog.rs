@@ -0,0 +1,154 @@
+// A logger for the log crate that writes hclog's JSON lines to stderr, which go-plugin hosts parse
+// and log through their own logger, at each line's level.
+// Modeled after: https://github.com/hashicorp/go-hclog/blob/master/intlogger.go
+use super::error::Error;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use nix::unistd::dup;
+use serde_json::{Map, Value};
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::sync::Mutex;
+use time::format_description::FormatItem;
+use time::macros::format_description;
+use time::OffsetDateTime;
+
+// Lowers the level, with one of hclog's level names. This crate's own, since go-plugin hosts don't
+// pass a level on to plugins.
+pub const ENV_PLUGIN_LOG_LEVEL: &str = "PLUGIN_LOG_LEVEL";
+
+// hclog's "2006-01-02T15:04:05.000000Z07:00", in UTC.
+const TIMESTAMP_FORMAT: &[FormatItem] =
+    format_description!("[year]-[month]-[day]T[hour]:[minute]:[second].[subsecond digits:6]Z");
+
+pub struct HcLogger {
+    name: String,
+    level: LevelFilter,
+    stderr: Mutex<File>,
+}
+
+impl HcLogger {
+    // Logs everything by default, like go-plugin's own plugin logger, since the host filters by
+    // level too. Serving redirects stderr to the host's stdio stream, so this holds on to stderr
+    // as it is now: create it before serving.
+    // Fails if PLUGIN_LOG_LEVEL isn't one of hclog's level names.
+    pub fn new(name: &str) -> Result<HcLogger, Error> {
+        let stderr = dup(std::io::stderr().as_raw_fd()).map_err(std::io::Error::from)?;
+        // Safe since dup just opened it, and nothing else owns it.
+        let stderr = unsafe { File::from_raw_fd(stderr) };
+
+        let level = match env::var(ENV_PLUGIN_LOG_LEVEL) {
+            Ok(level) => parse_level(&level).ok_or(Error::InvalidLogLevel(level))?,
+            Err(_) => LevelFilter::Trace,
+        };
+
+        Ok(HcLogger {
+            name: name.to_string(),
+            level,
+            stderr: Mutex::new(stderr),
+        })
+    }
+
+    pub fn with_level(mut self, level: LevelFilter) -> Self {
+        self.level = level;
+        self
+    }
+
+    // Makes this the logger for the log crate.
+    pub fn init(self) -> Result<(), Error> {
+        let level = self.level;
+        log::set_boxed_logger(Box::new(self))?;
+        log::set_max_level(level);
+        Ok(())
+    }
+
+    fn format(&self, record: &Record, timestamp: OffsetDateTime) -> String {
+        let mut line = Map::new();
+        line.insert(
+            "@level".to_string(),
+            Value::from(level_name(record.level())),
+        );
+        line.insert(
+            "@message".to_string(),
+            Value::from(record.args().to_string()),
+        );
+        line.insert("@module".to_string(), Value::from(self.name.as_str()));
+        line.insert(
+            "@timestamp".to_string(),
+            Value::from(timestamp.format(TIMESTAMP_FORMAT).unwrap_or_default()),
+        );
+        line.insert("target".to_string(), Value::from(record.target()));
+        Value::Object(line).to_string()
+    }
+}
+
+impl Log for HcLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let line = self.format(record, OffsetDateTime::now_utc());
+        // There's nowhere left to report failing to write the log.
+        let _ = writeln!(self.stderr.lock().unwrap(), "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = self.stderr.lock().unwrap().flush();
+    }
+}
+
+fn level_name(level: Level) -> &'static str {
+    match level {
+        Level::Error => "error",
+        Level::Warn => "warn",
+        Level::Info => "info",
+        Level::Debug => "debug",
+        Level::Trace => "trace",
+    }
+}
+
+// Same names as hclog.LevelFromString.
+fn parse_level(level: &str) -> Option<LevelFilter> {
+    match level.trim().to_lowercase().as_str() {
+        "trace" => Some(LevelFilter::Trace),
+        "debug" => Some(LevelFilter::Debug),
+        "info" => Some(LevelFilter::Info),
+        "warn" => Some(LevelFilter::Warn),
+        "error" => Some(LevelFilter::Error),
+        "off" => Some(LevelFilter::Off),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let logger = HcLogger::new("my-plugin").unwrap();
+        let timestamp =
+            OffsetDateTime::from_unix_timestamp_nanos(1_500_000_000_123_456_789).unwrap();
+        let line = logger.format(
+            &Record::builder()
+                .level(Level::Warn)
+                .target("grr_plugin::grpc_broker")
+                .args(format_args!("no \"listener\" for {}", 42))
+                .build(),
+            timestamp,
+        );
+
+        assert_eq!(
+            line,
+            r#"{"@level":"warn","@message":"no \"listener\" for 42","@module":"my-plugin","@timestamp":"2017-07-14T02:40:00.123456Z","target":"grr_plugin::grpc_broker"}"#
+        );
+        assert_eq!(parse_level("WARN"), Some(LevelFilter::Warn));
+        assert_eq!(parse_level("loud"), None);
+    }
+}
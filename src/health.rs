@@ -0,0 +1,129 @@
+// The plugin's health, served to the host over grpc.health.v1.Health. Plugins report each of
+// their services, and the plugin as a whole, serving or not through a HealthReporter, e.g. while
+// warming up or degraded.
+// Modeled after: https://github.com/hashicorp/go-plugin/blob/master/grpc_server.go
+use futures::future::BoxFuture;
+use http::{Request, Response};
+use hyper::Body;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tonic::body::BoxBody;
+use tonic::transport::NamedService;
+use tonic_health::ServingStatus;
+use tower::util::BoxCloneService;
+use tower::{Service, ServiceExt};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+// The service name go-plugin's host checks the health of the plugin by.
+// Copied from: https://github.com/hashicorp/go-plugin/blob/master/grpc_server.go#L21
+pub const PLUGIN_SERVICE_NAME: &str = "plugin";
+
+#[derive(Clone)]
+pub struct HealthReporter {
+    reporter: tonic_health::server::HealthReporter,
+    reported: Arc<Mutex<Reported>>,
+}
+
+#[derive(Default)]
+struct Reported {
+    services: HashSet<String>,
+    // Once shutting down, every service stays not serving.
+    shut_down: bool,
+}
+
+impl HealthReporter {
+    pub async fn set_serving(&self, service: &str) {
+        self.set_status(service, ServingStatus::Serving).await
+    }
+
+    pub async fn set_not_serving(&self, service: &str) {
+        self.set_status(service, ServingStatus::NotServing).await
+    }
+
+    // Notifies hosts watching the service if its status changed. Ignored once the plugin is
+    // shutting down.
+    pub async fn set_status(&self, service: &str, status: ServingStatus) {
+        {
+            let mut reported = self.reported.lock().unwrap();
+            if reported.shut_down {
+                log::debug!(
+                    "Not reporting {} as {:?}, since the plugin is shutting down.",
+                    service,
+                    status
+                );
+                return;
+            }
+            reported.services.insert(service.to_string());
+        }
+        log::debug!("Reporting {} as {:?}", service, status);
+        self.reporter
+            .clone()
+            .set_service_status(service, status)
+            .await;
+    }
+
+    // Reports a service the plugin hasn't reported on yet.
+    pub(crate) async fn set_default_status(&self, service: &str, status: ServingStatus) {
+        if self.reported.lock().unwrap().services.contains(service) {
+            return;
+        }
+        self.set_status(service, status).await
+    }
+
+    // Reports every service as not serving, for good.
+    pub(crate) async fn shut_down(&self) {
+        let services = {
+            let mut reported = self.reported.lock().unwrap();
+            reported.shut_down = true;
+            reported.services.clone()
+        };
+        let mut reporter = self.reporter.clone();
+        for service in services {
+            reporter
+                .set_service_status(service, ServingStatus::NotServing)
+                .await;
+        }
+    }
+}
+
+// tonic_health's server, under a type it can be kept as until serving.
+#[derive(Clone)]
+pub struct HealthService {
+    service: BoxCloneService<Request<Body>, Response<BoxBody>, BoxError>,
+}
+
+impl NamedService for HealthService {
+    const NAME: &'static str = "grpc.health.v1.Health";
+}
+
+impl Service<Request<Body>> for HealthService {
+    type Response = Response<BoxBody>;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        self.service.call(req)
+    }
+}
+
+pub fn new() -> (HealthReporter, HealthService) {
+    let (reporter, service) = tonic_health::server::health_reporter();
+    (
+        HealthReporter {
+            reporter,
+            reported: Arc::new(Mutex::new(Reported::default())),
+        },
+        HealthService {
+            service: BoxCloneService::new(ServiceExt::<Request<Body>>::map_err(
+                service,
+                Into::into,
+            )),
+        },
+    )
+}
@@ -1,38 +1,73 @@
 // A go-plugin Server to write Rust-based plugins to Golang.
 
+mod brokered_client;
+mod builder;
+pub mod client;
 pub mod error;
+pub mod gob;
 mod grpc_broker;
 mod grpc_broker_service;
 mod grpc_controller;
+mod grpc_mux;
 mod grpc_stdio;
+pub mod handshake;
+pub mod hclog;
+mod health;
+pub mod netrpc;
+mod plugin_set;
+mod shutdown;
+mod tcp;
+pub mod testing;
+mod tls;
+mod transport;
 mod unique_port;
 pub mod unix;
 
 use error::Error;
+use grpc_mux::GRpcServerMuxer;
+use grpc_stdio::StdioSource;
+use handshake::{Handshake, ReattachConfig, GRPC_CORE_PROTOCOL_VERSION};
+use health::HealthService;
 
-use anyhow::{anyhow, Context, Result};
+use futures::future::{Future, FutureExt};
+use futures::stream::StreamExt;
 use http::{Request, Response};
 use hyper::Body;
+use shutdown::{Drain, ShutdownHook};
 use std::clone::Clone;
 use std::env;
+use std::fs::File;
+use std::io::Write;
 use std::marker::Send;
+use tls::AutoMtls;
 use tonic::body::BoxBody;
 use tonic::transport::NamedService;
 use tower::Service;
-use unix::TempSocket;
+use transport::ListenConfig;
+use unique_port::UniquePort;
 
-pub use grpc_broker::GRpcBroker;
+pub use brokered_client::{BrokeredChannel, BrokeredClient, Interceptors};
+pub use builder::ServerBuilder;
+pub use client::Client;
+pub use grpc_broker::{BrokeredServer, GRpcBroker, DIAL_TIMEOUT};
 pub use grpc_broker_service::grpc_plugins::ConnInfo;
+pub use grpc_stdio::grpc_plugins::StdioData;
+pub use grpc_stdio::StdioWriter;
+pub use hclog::HcLogger;
+pub use health::{HealthReporter, PLUGIN_SERVICE_NAME};
+pub use netrpc::{NetRpcPlugins, NetRpcService};
+pub use plugin_set::{PluginSet, VersionedPlugins};
+pub use shutdown::ShutdownToken;
 pub use tonic::{Status, Streaming};
+pub use tonic_health::ServingStatus;
+pub use transport::{ConnectInfo, Transport};
+pub use unix::SocketConfig;
 
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
 
 pub type ServiceId = u32;
 
-// The constants are for generating the go-plugin string
-// https://github.com/hashicorp/go-plugin/blob/master/docs/guide-plugin-write-non-go.md
-const GRPC_CORE_PROTOCOL_VERSION: usize = 1;
-
 pub struct HandshakeConfig {
     pub magic_cookie_key: String,
     pub magic_cookie_value: String,
@@ -41,100 +76,174 @@ pub struct HandshakeConfig {
 pub struct Server {
     handshake_config: HandshakeConfig,
     protocol_version: u32,
-    outgoing_conninfo_sender_receiver: UnboundedReceiver<UnboundedSender<Result<ConnInfo, Status>>>,
-    outgoing_conninfo_receiver_receiver:
-        UnboundedReceiver<UnboundedReceiver<Result<ConnInfo, Status>>>,
+    // ConnInfo's the broker sends the host, through the GRPCBroker service's stream.
+    outgoing_conninfo_sender: UnboundedSender<Result<ConnInfo, Status>>,
+    // Taken when serving starts.
+    outgoing_conninfo_receiver: Option<UnboundedReceiver<Result<ConnInfo, Status>>>,
+    // The host's stream of ConnInfo's, from the GRPCBroker service to the broker.
     incoming_conninfo_stream_sender: UnboundedSender<Streaming<ConnInfo>>,
-    incoming_conninfo_stream_receiver_receiver:
-        UnboundedReceiver<UnboundedReceiver<Streaming<ConnInfo>>>,
+    // Taken by the broker, once it's first asked for.
+    incoming_conninfo_stream_receiver: Option<UnboundedReceiver<Streaming<ConnInfo>>>,
+    broker: Option<GRpcBroker>,
     trigger: triggered::Trigger,
     listener: triggered::Listener,
+    auto_mtls: Option<AutoMtls>,
+    listen_config: ListenConfig,
+    muxer: Option<GRpcServerMuxer>,
+    mode: ServeMode,
+    stdio: StdioSource,
+    // Whether the plugin is reported as serving when it starts.
+    serving: bool,
+    health: HealthReporter,
+    health_service: HealthService,
+    // Lets in-flight calls finish once shutdown starts.
+    drain: Drain,
+    shutdown_hooks: Vec<ShutdownHook>,
+    // Whether our stdin closing means the host is gone.
+    watch_stdin: bool,
+    // Whether SIGINT and SIGTERM are handled, as go-plugin's are.
+    handle_signals: bool,
+}
+
+// Who the plugin is being served to, which decides how it expects to be found.
+enum ServeMode {
+    // A host that launched us, and reads the handshake from our stdout.
+    Host,
+    // Nobody yet. See ServerBuilder::debug.
+    Debug,
+    // A test in this process, which gets the handshake sent to it. See testing::TestServer.
+    Test(Option<oneshot::Sender<Handshake>>),
 }
 
 impl Server {
+    // A server configured the way the host asks, through the environment. See ServerBuilder
+    // for more options.
     pub fn new(protocol_version: u32, handshake_config: HandshakeConfig) -> Result<Server, Error> {
-        // This channel sends conninfo from the plugin/server side (the sender will be vended to the JsonRPCBroker who will send new
-        // ConnInfo's as new services/handlers are launched) to the host/client side (through the gRPCBroker's start_stream call)
-        // where the host/client will process them
-        let (outgoing_conninfo_sender, outgoing_conninfo_receiver) =
-            unbounded_channel::<Result<ConnInfo, Status>>();
-
-        // Use this channel to send the channel transmitter above from this constructor
-        // to where it will be consumed in the "jsonrpc_broker" method later...
-        // using channels avoids having to do a complex sync dance using mutable globals.
-        let (outgoing_conninfo_sender_transmitter, outgoing_conninfo_sender_receiver) =
-            unbounded_channel();
-        outgoing_conninfo_sender_transmitter.send(outgoing_conninfo_sender).context("Unable to send the outgoing_conninfo_sender to the transmitter. This is a tokio mpsc channel's transmitter being transmitted over another channel so it can be consumed exactly-one by someone later.")?;
-
-        // Use this channel to send the channel receiver above from this constructor
-        // to where it will be consumed in the "serve" method later...
-        // using channels avoids having to do a complex sync dance using mutable globals.
-        let (outgoing_conninfo_receiver_transmitter, outgoing_conninfo_receiver_receiver) =
-            unbounded_channel();
-        outgoing_conninfo_receiver_transmitter.send(outgoing_conninfo_receiver).context("Unable to send the outgoing_conninfo_receiver to the transmitter. This is a tokio mpsc channel's receiver being transmitted over another channel so it can be consumed exactly-one by someone later.")?;
+        ServerBuilder::new(protocol_version, handshake_config).build()
+    }
 
-        // Use this channel to send the channel from where we will receive ConnInfo's coming inbound
-        // from the host, to the broker which will know what to do with them
-        // This channel/stream of ConnInfo's will be received from the GRPCBroker in the start_stream call
-        // and will be sent from there to the JsonRPCBroker who will broker the ConnInfo's towards the host.
+    // A server that reads nothing from the environment, since no host set it up for us.
+    fn without_env(
+        protocol_version: u32,
+        handshake_config: HandshakeConfig,
+    ) -> Result<Server, Error> {
+        // ConnInfo's of the servers we broker go out to the host over one channel, and the
+        // host's stream of its own ConnInfo's comes in over the other.
+        let (outgoing_conninfo_sender, outgoing_conninfo_receiver) = unbounded_channel();
         let (incoming_conninfo_stream_sender, incoming_conninfo_stream_receiver) =
             unbounded_channel();
 
-        // Do the same dance of channel-of-channels to send the receiver since the underlying stream won't be available
-        // for quite some time.
-        let (
-            incoming_conninfo_stream_receiver_transmitter,
-            incoming_conninfo_stream_receiver_receiver,
-        ) = unbounded_channel();
-
-        incoming_conninfo_stream_receiver_transmitter.send(incoming_conninfo_stream_receiver)
-            .context("Unable to send the incoming_conninfo_stream_receiver to the transmitter. This is a tokio mpsc channel's receiver's receiver being transmitted over another channel so it can be consumed exactly-one by someone later. They will eventually listen to this channel to then get the actual stream over which they'll receive incoming ConnInfo's.")?;
-
         let (trigger, listener) = triggered::trigger();
+        let (health, health_service) = health::new();
 
         Ok(Server {
             handshake_config,
             protocol_version,
-            outgoing_conninfo_sender_receiver,
-            outgoing_conninfo_receiver_receiver,
+            outgoing_conninfo_sender,
+            outgoing_conninfo_receiver: Some(outgoing_conninfo_receiver),
             incoming_conninfo_stream_sender,
-            incoming_conninfo_stream_receiver_receiver,
+            incoming_conninfo_stream_receiver: Some(incoming_conninfo_stream_receiver),
+            broker: None,
             trigger,
-            listener,
+            listener: listener.clone(),
+            auto_mtls: None,
+            listen_config: ListenConfig::default(),
+            muxer: None,
+            mode: ServeMode::Host,
+            stdio: StdioSource::default(),
+            serving: true,
+            health,
+            health_service,
+            drain: Drain::new(listener.clone()),
+            shutdown_hooks: Vec::new(),
+            watch_stdin: false,
+            handle_signals: true,
         })
     }
 
+    // A server for a test in this process, which sends it the handshake once serving, and
+    // streams what's written to the stdio writers instead of our real stdout and stderr.
+    pub(crate) fn for_test(
+        protocol_version: u32,
+        handshake_sender: oneshot::Sender<Handshake>,
+        stdio: StdioSource,
+    ) -> Result<Server, Error> {
+        let mut server = Server::without_env(
+            protocol_version,
+            HandshakeConfig {
+                magic_cookie_key: String::new(),
+                magic_cookie_value: String::new(),
+            },
+        )?;
+        server.mode = ServeMode::Test(Some(handshake_sender));
+        server.stdio = stdio;
+        Ok(server)
+    }
+
+    // Runs hook once the plugin has shut down and in-flight calls have drained, before serving
+    // returns. Hooks run one at a time, in the order they were added.
+    pub fn on_shutdown<F, Fut>(&mut self, hook: F)
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.shutdown_hooks.push(Box::new(move || hook().boxed()));
+    }
+
+    pub fn shutdown_token(&self) -> ShutdownToken {
+        ShutdownToken::new(self.listener.clone())
+    }
+
+    // Reports the health of the plugin's services, and of the plugin as a whole under
+    // PLUGIN_SERVICE_NAME, to the host. Services not reported on before serving starts are
+    // reported serving, unless ServerBuilder::serving turned that off.
+    pub fn health_reporter(&self) -> HealthReporter {
+        self.health.clone()
+    }
+
+    // Picks the protocol version to serve from the ones the host lists in PLUGIN_PROTOCOL_VERSIONS,
+    // and returns the server along with the plugin set to serve for that version.
+    pub fn new_versioned(
+        handshake_config: HandshakeConfig,
+        mut versioned_plugins: VersionedPlugins,
+    ) -> Result<(Server, PluginSet), Error> {
+        let protocol_version = plugin_set::negotiate_protocol_version(&versioned_plugins)?;
+        log::info!("Negotiated protocol version {}", protocol_version);
+
+        let plugin_set = versioned_plugins
+            .remove(&protocol_version)
+            .ok_or(Error::NoPluginVersions)?;
+
+        Ok((Server::new(protocol_version, handshake_config)?, plugin_set))
+    }
+
+    // The plugin's side of the broker, shared by every call. Take it before serving, e.g. to hand
+    // it to the plugin being served. It brokers with the options the server had when it was
+    // first taken.
     pub async fn grpc_broker(&mut self) -> Result<GRpcBroker, Error> {
-        let outgoing_conninfo_sender = match self.outgoing_conninfo_sender_receiver.recv().await {
-            None => {
-                let err = anyhow!("jsonrpc_server_broker's transmission channel was None, which, being initalized in the constructor, was vended off already. Was this method called twice? Did someone else .recv() it?");
-                log::error!("{}", err);
-                return Err(Error::Other(err));
-            }
-            Some(outgoing_conninfo_sender) => outgoing_conninfo_sender,
-        };
+        if let Some(broker) = &self.broker {
+            return Ok(broker.clone());
+        }
 
-        let incoming_conninfo_stream_receiver = match self
-            .incoming_conninfo_stream_receiver_receiver
-            .recv()
-            .await
-        {
-            None => {
-                let err = anyhow!("jsonrpc_server_broker's receiver for a future incoming stream of ConnInfo was None, which, being initalized in the constructor, was vended off already.");
-                log::error!("{}", err);
-                return Err(Error::Other(err));
-            }
-            Some(outgoing_conninfo_sender) => outgoing_conninfo_sender,
-        };
+        let unique_port = self.unique_port()?;
+        // The host's ConnInfo's are only taken by the one broker, which is kept above.
+        let incoming_conninfo_stream_receiver = self
+            .incoming_conninfo_stream_receiver
+            .take()
+            .ok_or(Error::BrokerStreamAlreadyStarted)?;
 
         // create the JSON-RPC 2.0 server broker
         log::trace!("Creating the JSON RPC 2.0 Server Broker.",);
         let jsonrpc_broker = GRpcBroker::new(
-            unique_port::UniquePort::new(),
-            outgoing_conninfo_sender,
+            unique_port,
+            self.listen_config.clone(),
+            self.outgoing_conninfo_sender.clone(),
             incoming_conninfo_stream_receiver,
-            self.listener.clone(),
+            self.drain.clone(),
+            self.auto_mtls.clone(),
+            self.muxer.clone(),
         );
+        self.broker = Some(jsonrpc_broker.clone());
 
         log::info!("Created JSON RPC 2.0 Server Broker.");
 
@@ -143,6 +252,15 @@ impl Server {
 
     // Copied from: https://github.com/hashicorp/go-plugin/blob/master/server.go#L247
     fn validate_magic_cookie(&self) -> Result<(), Error> {
+        match self.mode {
+            ServeMode::Host => {}
+            ServeMode::Debug => {
+                log::info!("Serving in debug mode, so not expecting a magic cookie from a host.");
+                return Ok(());
+            }
+            ServeMode::Test(_) => return Ok(()),
+        }
+
         log::info!("Validating the magic environment cookies to conduct the handshake. Expecting environment variable {}={}.", self.handshake_config.magic_cookie_key, self.handshake_config.magic_cookie_value);
         match env::var(&self.handshake_config.magic_cookie_key) {
             Ok(value) => {
@@ -163,6 +281,148 @@ impl Server {
         Err(Error::GRPCHandshakeMagicCookieValueMismatch)
     }
 
+    // Starts shutting down when the host goes away, or signals us to. Tests share our process,
+    // so its signals aren't theirs to handle.
+    fn watch_host(&self) -> Result<(), Error> {
+        match self.mode {
+            ServeMode::Host => {
+                if self.watch_stdin {
+                    shutdown::shutdown_on_stdin_close(self.trigger.clone());
+                }
+                if self.handle_signals {
+                    shutdown::shutdown_on_signals(self.trigger.clone(), true)?;
+                }
+            }
+            ServeMode::Debug => {
+                if self.handle_signals {
+                    shutdown::shutdown_on_signals(self.trigger.clone(), false)?;
+                }
+            }
+            ServeMode::Test(_) => {}
+        }
+        Ok(())
+    }
+
+    // However serving ended, even when it failed to start, shuts down the rest of the way, so
+    // the ShutdownToken resolves and the hooks run. Brokered servers don't outlive the main one,
+    // and get the same deadline to drain.
+    async fn finish_shutdown(&mut self) {
+        self.trigger.trigger();
+        self.drain.wait().await;
+        self.run_shutdown_hooks().await;
+    }
+
+    async fn run_shutdown_hooks(&mut self) {
+        let hooks: Vec<ShutdownHook> = self.shutdown_hooks.drain(..).collect();
+        log::info!("Running {} shutdown hooks...", hooks.len());
+        for hook in hooks {
+            hook().await;
+        }
+    }
+
+    // Tests pick their own ports, rather than the ones a host would allow.
+    fn unique_port(&self) -> Result<UniquePort, Error> {
+        match self.mode {
+            ServeMode::Test(_) => Ok(UniquePort::new()),
+            _ => UniquePort::from_env(),
+        }
+    }
+
+    fn handshake(&self, network: String, address: String, protocol: &str) -> Handshake {
+        Handshake {
+            core_protocol_version: GRPC_CORE_PROTOCOL_VERSION,
+            app_protocol_version: self.protocol_version,
+            network,
+            address,
+            protocol: protocol.to_string(),
+            server_cert: self
+                .auto_mtls
+                .as_ref()
+                .map(|auto_mtls| auto_mtls.server_cert().to_string()),
+            multiplex_grpc: false,
+        }
+    }
+
+    // Tells the host where we're listening. In debug mode there's no host yet, so print what
+    // one needs to attach.
+    // stdout is where the process's stdout went before it was captured for the host.
+    fn announce(&mut self, handshake: &Handshake, stdout: Option<File>) {
+        match &mut self.mode {
+            ServeMode::Host => {
+                let handshakestr = handshake.to_string();
+                log::info!("About to print handshake string: {}", handshakestr);
+                print_line(stdout, &handshakestr);
+                return;
+            }
+            ServeMode::Test(handshake_sender) => {
+                if let Some(handshake_sender) = handshake_sender.take() {
+                    let _ = handshake_sender.send(handshake.clone());
+                }
+                return;
+            }
+            ServeMode::Debug => {}
+        }
+
+        let reattach = ReattachConfig::new(handshake);
+        log::info!(
+            "Plugin started in debug mode. A host can attach to it with: {}",
+            reattach
+        );
+        print_line(stdout, &reattach.to_string());
+    }
+
+    // Serves go-plugin's net/rpc protocol instead of gRPC, for hosts that don't speak gRPC.
+    // The host dispenses the plugins by name.
+    pub async fn serve_netrpc(&mut self, plugins: NetRpcPlugins) -> Result<(), Error> {
+        let result = self.serve_netrpc_until_shutdown(plugins).await;
+        self.finish_shutdown().await;
+        result
+    }
+
+    async fn serve_netrpc_until_shutdown(&mut self, plugins: NetRpcPlugins) -> Result<(), Error> {
+        log::trace!("serving net/rpc over {:?}...", self.listen_config.transport);
+
+        self.validate_magic_cookie()?;
+
+        let (output, stdout) = self.stdio.capture()?;
+        self.watch_host()?;
+
+        let mut unique_port = self.unique_port()?;
+        let transport::Listener {
+            network,
+            address,
+            incoming,
+        } = transport::listen(&self.listen_config, &mut unique_port)
+            .await
+            .inspect_err(|e| {
+                log::error!("Failed to open a listener for the net/rpc server: {}", e)
+            })?;
+        log::trace!("Listening for net/rpc on {}:{}", network, address);
+
+        let handshake = self.handshake(network, address, "netrpc");
+        let mut incoming = Box::pin(tls::incoming(incoming, self.auto_mtls.clone()));
+
+        self.announce(&handshake, stdout);
+
+        let listener = self.listener.clone();
+        loop {
+            tokio::select! {
+                conn = incoming.next() => match conn {
+                    Some(Ok(conn)) => {
+                        log::info!("Accepted a net/rpc connection from the host");
+                        tokio::spawn(netrpc::serve_conn(conn, plugins.clone(), output.clone(), self.trigger.clone()));
+                    }
+                    Some(Err(e)) => log::error!("Failed to accept a net/rpc connection: {}", e),
+                    None => break,
+                },
+                _ = listener.clone() => break,
+            }
+        }
+
+        log::info!("net/rpc service ended");
+        Ok(())
+    }
+
     pub async fn serve<S>(&mut self, plugin: S) -> Result<(), Error>
     where
         S: Service<Request<Body>, Response = Response<BoxBody>>
@@ -174,33 +434,103 @@ impl Server {
         <S as Service<http::Request<hyper::Body>>>::Error:
             Into<Box<dyn std::error::Error + Send + Sync>> + Send,
     {
-        log::trace!("serving over a Tcp Socket...");
+        self.serve_named(plugin, vec![S::NAME]).await
+    }
+
+    // Serves every service in the set, with the health service reporting on each of them,
+    // as well as on the plugin as a whole.
+    pub async fn serve_plugins(&mut self, plugin_set: PluginSet) -> Result<(), Error> {
+        let names = plugin_set.names().collect();
+        self.serve_named(plugin_set, names).await
+    }
+
+    // names are the services the health service reports on, along with the plugin as a whole.
+    async fn serve_named<S>(&mut self, plugin: S, names: Vec<&'static str>) -> Result<(), Error>
+    where
+        S: Service<Request<Body>, Response = Response<BoxBody>>
+            + NamedService
+            + Clone
+            + Send
+            + 'static,
+        <S as Service<http::Request<hyper::Body>>>::Future: Send + 'static,
+        <S as Service<http::Request<hyper::Body>>>::Error:
+            Into<Box<dyn std::error::Error + Send + Sync>> + Send,
+    {
+        let result = self.serve_named_until_shutdown(plugin, names).await;
+        self.finish_shutdown().await;
+        result
+    }
+
+    async fn serve_named_until_shutdown<S>(
+        &mut self,
+        plugin: S,
+        names: Vec<&'static str>,
+    ) -> Result<(), Error>
+    where
+        S: Service<Request<Body>, Response = Response<BoxBody>>
+            + NamedService
+            + Clone
+            + Send
+            + 'static,
+        <S as Service<http::Request<hyper::Body>>>::Future: Send + 'static,
+        <S as Service<http::Request<hyper::Body>>>::Error:
+            Into<Box<dyn std::error::Error + Send + Sync>> + Send,
+    {
+        log::trace!("serving over {:?}...", self.listen_config.transport);
+
+        self.validate_magic_cookie()?;
 
-        self.validate_magic_cookie().context("Failed to validate magic cookie handshake from plugin client (i.e. host, i.e. consumer) to this Plugin.")?;
+        let status = match self.serving {
+            true => ServingStatus::Serving,
+            false => ServingStatus::NotServing,
+        };
+        for name in names.iter().chain(["", PLUGIN_SERVICE_NAME].iter()) {
+            self.health.set_default_status(name, status).await;
+        }
 
-        let (mut health_reporter, health_service) = tonic_health::server::health_reporter();
-        health_reporter.set_serving::<S>().await;
+        // Hosts checking on us during shutdown hear we're going away.
+        let listener = self.listener.clone();
+        let health = self.health.clone();
+        tokio::spawn(async move {
+            listener.await;
+            health.shut_down().await;
+        });
         log::info!("gRPC Health Service created.");
 
-        let temp_socket = TempSocket::new()
-            .context("Failed to create a new TempSocket for opening the main gRPC listener to")?;
-        let socket_path = temp_socket.socket_filename()
-            .context("Failed to get a temporary socket filename from the temp socket for opening the main gRPC listener to")?;
-        log::trace!("Created new temp socket: {}", socket_path);
+        let (output, stdout) = self.stdio.capture()?;
+        self.watch_host()?;
 
-        let handshakestr = format!(
-            "{}|{}|unix|{}|grpc|",
-            GRPC_CORE_PROTOCOL_VERSION, self.protocol_version, socket_path,
+        let mut unique_port = self.unique_port()?;
+        let transport::Listener {
+            network,
+            address,
+            incoming,
+        } = transport::listen(&self.listen_config, &mut unique_port)
+            .await
+            .inspect_err(|e| {
+                log::error!("Failed to open a listener for the main gRPC server: {}", e)
+            })?;
+        log::trace!(
+            "Listening for the main gRPC server on {}:{}",
+            network,
+            address
         );
-        log::trace!("Created Handshake string: {}", handshakestr);
 
-        // create incoming stream from unix socket above...
-        let incoming_stream_from_socket = unix::incoming_from_path(socket_path.as_str()).await?;
-        log::trace!("Created Incoming unix stream from the socket");
+        let handshake = Handshake {
+            multiplex_grpc: self.muxer.is_some(),
+            ..self.handshake(network, address, "grpc")
+        };
+
+        // TLS, when on, runs inside each multiplexed stream rather than around the session.
+        let incoming = match &self.muxer {
+            Some(muxer) => muxer.serve(incoming),
+            None => incoming,
+        };
+        let incoming_stream = tls::incoming(incoming, self.auto_mtls.clone());
 
-        let outgoing_conninfo_receiver = match self.outgoing_conninfo_receiver_receiver.recv().await {
+        let outgoing_conninfo_receiver = match self.outgoing_conninfo_receiver.take() {
             Some(outgoing_conninfo_receiver) => outgoing_conninfo_receiver,
-            None => return Err(Error::Other(anyhow!("Outgoing ConnInfo receiver does not exist. Did someone else .recv() it before? It was created in the constructor, so should be available in the method."))),
+            None => return Err(Error::AlreadyServed),
         };
 
         log::info!("Creating a GRPC Broker Server.");
@@ -208,33 +538,55 @@ impl Server {
         let broker_server = grpc_broker_service::new_server(
             outgoing_conninfo_receiver,
             self.incoming_conninfo_stream_sender.clone(),
+            self.listener.clone(),
         )
         .await?;
 
         log::info!("Creating a GRPC Controller Server.");
         let controller_server = grpc_controller::new_server(self.trigger.clone());
         log::info!("Creating a GRPC Stdio Server.");
-        let stdio_server = grpc_stdio::new_server();
+        let stdio_server = grpc_stdio::new_server(output, self.listener.clone());
 
         let listener = self.listener.clone();
         log::info!("Starting service...");
+        // The plugin goes first, so that a PluginSet, which claims every request not
+        // routed elsewhere, doesn't shadow the go-plugin services.
         let grpc_service_future = tonic::transport::Server::builder()
-            .add_service(health_service)
+            .add_service(plugin)
+            .add_service(self.health_service.clone())
             .add_service(broker_server)
             .add_service(controller_server)
             .add_service(stdio_server)
-            .add_service(plugin)
-            .serve_with_incoming_shutdown(incoming_stream_from_socket, async { listener.await });
+            .serve_with_incoming_shutdown(incoming_stream, listener.clone());
 
-        log::info!("About to print handshake string: {}", handshakestr);
-        println!("{}", handshakestr);
+        self.announce(&handshake, stdout);
 
         // starting broker and plugin services now...
         //join!(broker_service_future, plugin_service_future);
-        let result = grpc_service_future.await;
+        let result = tokio::select! {
+            result = grpc_service_future => result,
+            _ = self.drain.deadline() => {
+                log::warn!(
+                    "In-flight calls didn't finish within {:?} of shutting down. Stopping anyway.",
+                    self.drain.grace_period()
+                );
+                Ok(())
+            }
+        };
 
         log::info!("gRPC broker service ended with result: {:?}", result);
+        result.map_err(Error::TonicTransport)
+    }
+}
 
-        Ok(())
+// Prints to the process's original stdout, if it was captured.
+fn print_line(stdout: Option<File>, line: &str) {
+    match stdout {
+        None => println!("{}", line),
+        Some(mut stdout) => {
+            if let Err(e) = writeln!(stdout, "{}", line) {
+                log::error!("Unable to print to stdout: {}", e);
+            }
+        }
     }
 }
@@ -0,0 +1,505 @@
+// go-plugin's original protocol, for hosts that predate gRPC plugins: Go's net/rpc, gob encoded,
+// over a yamux session on the host's connection. The first stream the host opens serves the Control
+// and Dispenser services, the next two carry our stdout and stderr, and the rest are brokered by id.
+// Copied from: https://github.com/hashicorp/go-plugin/blob/master/rpc_server.go
+use super::error::Error;
+use super::gob::{Decoder, Encoder, Value};
+use super::grpc_stdio::grpc_plugins::stdio_data::Channel;
+use super::grpc_stdio::StdioOutput;
+use futures::stream::StreamExt;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::oneshot;
+use tokio::time::{sleep, timeout};
+use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+use tonic::async_trait;
+
+// How long a brokered stream and whoever accepts its id wait for each other. Same as go-plugin.
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(5);
+
+type Stream = Compat<yamux::Stream>;
+
+// A service served over net/rpc, which is handed every call to one of its methods.
+// Calls may be concurrent, as they are in Go.
+#[async_trait]
+pub trait NetRpcService: Send + Sync + 'static {
+    // method is the name after the service's, e.g. "Echo" for "Plugin.Echo". An Err is
+    // sent to the caller as the call's error.
+    async fn call(&self, method: &str, args: Value) -> Result<Value, String>;
+}
+
+// The plugins a host can dispense, by name. Each is served as "Plugin" on its own brokered stream.
+pub type NetRpcPlugins = HashMap<String, Arc<dyn NetRpcService>>;
+
+pub(crate) async fn serve_conn<IO>(
+    conn: IO,
+    plugins: NetRpcPlugins,
+    output: StdioOutput,
+    trigger: triggered::Trigger,
+) where
+    IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let session =
+        yamux::Connection::new(conn.compat(), yamux::Config::default(), yamux::Mode::Server);
+    let mut streams =
+        Box::pin(yamux::into_stream(session).map(|stream| stream.map(|st| st.compat())));
+
+    let control = streams.next().await;
+    let stdout = streams.next().await;
+    let stderr = streams.next().await;
+    let (control, stdout, stderr) = match (control, stdout, stderr) {
+        (Some(Ok(control)), Some(Ok(stdout)), Some(Ok(stderr))) => {
+            match (
+                acknowledge(control).await,
+                acknowledge(stdout).await,
+                acknowledge(stderr).await,
+            ) {
+                (Ok(control), Ok(stdout), Ok(stderr)) => (control, stdout, stderr),
+                _ => {
+                    log::error!("net/rpc host closed the control and stdio streams");
+                    return;
+                }
+            }
+        }
+        _ => {
+            log::error!("net/rpc host didn't open the control and stdio streams");
+            return;
+        }
+    };
+
+    tokio::spawn(forward_output(output, stdout, stderr));
+
+    let broker = MuxBroker::default();
+    let control_server = RpcServer::default()
+        .register("Control", Arc::new(ControlService { trigger }))
+        .register(
+            "Dispenser",
+            Arc::new(DispenserService {
+                broker: broker.clone(),
+                plugins,
+            }),
+        );
+    tokio::spawn(control_server.serve(control));
+
+    while let Some(stream) = streams.next().await {
+        match stream {
+            Ok(stream) => {
+                tokio::spawn(broker.clone().route(stream));
+            }
+            Err(e) => {
+                log::error!("net/rpc yamux session failed: {}", e);
+                break;
+            }
+        }
+    }
+    log::info!("net/rpc host connection ended");
+}
+
+// Go's yamux closes the session when a stream it opened isn't acknowledged in time, and yamux
+// here only acknowledges with the first frame it sends, so send an empty one straight away.
+async fn acknowledge(mut stream: Stream) -> std::io::Result<Stream> {
+    let _empty = stream.write(&[]).await?;
+    Ok(stream)
+}
+
+// Copies our output to the host, for as long as it's reading.
+async fn forward_output(output: StdioOutput, mut stdout: Stream, mut stderr: Stream) {
+    let output = output.lock().unwrap().take();
+    let mut output = match output {
+        Some(output) => output,
+        None => {
+            log::warn!("Our output is already being copied to another net/rpc host connection");
+            return;
+        }
+    };
+
+    while let Some(data) = output.recv().await {
+        let stream = match data.channel {
+            c if c == Channel::Stderr as i32 => &mut stderr,
+            _ => &mut stdout,
+        };
+        if let Err(e) = stream.write_all(&data.data).await {
+            log::debug!("Host stopped reading our output: {}", e);
+            break;
+        }
+    }
+}
+
+// Serves net/rpc services on a stream, like Go's rpc.Server.ServeConn with the gob codec.
+#[derive(Clone, Default)]
+struct RpcServer {
+    services: HashMap<&'static str, Arc<dyn NetRpcService>>,
+}
+
+impl RpcServer {
+    fn register(mut self, name: &'static str, service: Arc<dyn NetRpcService>) -> Self {
+        self.services.insert(name, service);
+        self
+    }
+
+    async fn serve<IO>(self, io: IO)
+    where
+        IO: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (reader, writer) = tokio::io::split(io);
+        let mut reader = BufReader::new(reader);
+        let mut decoder = Decoder::new();
+        let writer = Arc::new(tokio::sync::Mutex::new((writer, Encoder::new())));
+
+        loop {
+            // Every request is a header, then the arguments.
+            let (header, args) = match decoder.decode(&mut reader).await {
+                Ok(header) => match decoder.decode(&mut reader).await {
+                    Ok(args) => (header, args),
+                    Err(e) => {
+                        log::error!("net/rpc failed to read arguments: {}", e);
+                        break;
+                    }
+                },
+                Err(e) => {
+                    log::debug!("net/rpc connection closed: {}", e);
+                    break;
+                }
+            };
+
+            let (service_method, seq) = match (header.field("ServiceMethod"), header.field("Seq")) {
+                (Some(Value::String(service_method)), Some(Value::Uint(seq))) => {
+                    (service_method.clone(), *seq)
+                }
+                _ => {
+                    log::error!("net/rpc received an invalid request header: {:?}", header);
+                    break;
+                }
+            };
+            log::trace!("net/rpc call {} ({})", service_method, seq);
+
+            let services = self.services.clone();
+            let writer = writer.clone();
+            tokio::spawn(async move {
+                let result = match service_method.split_once('.') {
+                    Some((service, method)) => match services.get(service) {
+                        Some(service) => service.call(method, args).await,
+                        None => Err(format!("rpc: can't find service {}", service_method)),
+                    },
+                    None => Err(format!(
+                        "rpc: service/method request ill-formed: {}",
+                        service_method
+                    )),
+                };
+
+                // Go sends an empty struct as the reply to a call that failed.
+                let (error, reply) = match result {
+                    Ok(reply) => (String::new(), reply),
+                    Err(error) => (error, Value::empty_struct()),
+                };
+                let response = Value::Struct(
+                    "Response".to_string(),
+                    vec![
+                        (
+                            "ServiceMethod".to_string(),
+                            Value::String(service_method.clone()),
+                        ),
+                        ("Seq".to_string(), Value::Uint(seq)),
+                        ("Error".to_string(), Value::String(error)),
+                    ],
+                );
+
+                let mut writer = writer.lock().await;
+                let (io, encoder) = &mut *writer;
+                let mut message = encoder.encode(&response);
+                message.extend(encoder.encode(&reply));
+                if let Err(e) = io.write_all(&message).await {
+                    log::error!("net/rpc failed to reply to {}: {}", service_method, e);
+                }
+            });
+        }
+    }
+}
+
+// Copied from: https://github.com/hashicorp/go-plugin/blob/master/rpc_server.go#L143
+struct ControlService {
+    trigger: triggered::Trigger,
+}
+
+#[async_trait]
+impl NetRpcService for ControlService {
+    async fn call(&self, method: &str, _args: Value) -> Result<Value, String> {
+        match method {
+            "Ping" => Ok(Value::empty_struct()),
+            "Quit" => {
+                log::info!("Quit called. Stopping net/rpc server...");
+                self.trigger.trigger();
+                Ok(Value::empty_struct())
+            }
+            _ => Err(format!("rpc: can't find method Control.{}", method)),
+        }
+    }
+}
+
+struct DispenserService {
+    broker: MuxBroker,
+    plugins: NetRpcPlugins,
+}
+
+#[async_trait]
+impl NetRpcService for DispenserService {
+    // Replies with the id of a brokered stream, on which the plugin will be served.
+    async fn call(&self, method: &str, args: Value) -> Result<Value, String> {
+        if method != "Dispense" {
+            return Err(format!("rpc: can't find method Dispenser.{}", method));
+        }
+        let name = match args {
+            Value::String(name) => name,
+            args => return Err(format!("expected a plugin name, got {:?}", args)),
+        };
+        let plugin = self
+            .plugins
+            .get(&name)
+            .cloned()
+            .ok_or_else(|| format!("unknown plugin type: {}", name))?;
+
+        let id = self.broker.next_id();
+        let broker = self.broker.clone();
+        // The host dials the id once this call returns.
+        tokio::spawn(async move {
+            match broker.accept(id).await {
+                Ok(stream) => {
+                    RpcServer::default()
+                        .register("Plugin", plugin)
+                        .serve(stream)
+                        .await
+                }
+                Err(e) => log::error!("Plugin dispense error: {}", e),
+            }
+        });
+
+        Ok(Value::Uint(id as u64))
+    }
+}
+
+// Hands out streams from the host by the id it writes at the start of each.
+// Copied from: https://github.com/hashicorp/go-plugin/blob/master/mux_broker.go
+#[derive(Clone, Default)]
+struct MuxBroker {
+    next_id: Arc<AtomicU32>,
+    pending: Arc<Mutex<HashMap<u32, Pending>>>,
+}
+
+enum Pending {
+    Waiting(oneshot::Sender<Stream>),
+    Arrived(Stream),
+}
+
+impl MuxBroker {
+    fn next_id(&self) -> u32 {
+        self.next_id.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    async fn route(self, stream: Stream) {
+        let id = match acknowledge(stream).await {
+            Ok(mut stream) => stream.read_u32_le().await.map(|id| (id, stream)),
+            Err(e) => Err(e),
+        };
+        let (id, stream) = match id {
+            Ok(id) => id,
+            Err(e) => {
+                log::error!("Error reading stream ID: {}", e);
+                return;
+            }
+        };
+
+        {
+            let mut pending = self.pending.lock().unwrap();
+            match pending.remove(&id) {
+                Some(Pending::Waiting(sender)) => {
+                    let _ = sender.send(stream);
+                    return;
+                }
+                _ => {
+                    pending.insert(id, Pending::Arrived(stream));
+                }
+            }
+        }
+
+        // Drop the stream if nobody accepts it in time.
+        sleep(ACCEPT_TIMEOUT).await;
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(Pending::Arrived(_)) = pending.get(&id) {
+            log::warn!("Stream {} was never accepted", id);
+            pending.remove(&id);
+        }
+    }
+
+    async fn accept(&self, id: u32) -> Result<Stream, Error> {
+        let arrived = {
+            let mut pending = self.pending.lock().unwrap();
+            match pending.remove(&id) {
+                Some(Pending::Arrived(stream)) => Ok(stream),
+                _ => {
+                    let (sender, receiver) = oneshot::channel();
+                    pending.insert(id, Pending::Waiting(sender));
+                    Err(receiver)
+                }
+            }
+        };
+        let receiver = match arrived {
+            Ok(stream) => return ack(stream, id).await,
+            Err(receiver) => receiver,
+        };
+
+        match timeout(ACCEPT_TIMEOUT, receiver).await {
+            Ok(Ok(stream)) => ack(stream, id).await,
+            _ => {
+                self.pending.lock().unwrap().remove(&id);
+                Err(Error::NetRpc(format!(
+                    "timeout waiting for accept of stream {}",
+                    id
+                )))
+            }
+        }
+    }
+}
+
+// The dialer waits to read back the id it wrote.
+async fn ack(mut stream: Stream, id: u32) -> Result<Stream, Error> {
+    stream.write_u32_le(id).await?;
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::grpc_stdio::StdioSource;
+    use std::io::Write;
+
+    struct EchoService;
+
+    #[async_trait]
+    impl NetRpcService for EchoService {
+        async fn call(&self, method: &str, args: Value) -> Result<Value, String> {
+            match method {
+                "Echo" => Ok(args),
+                _ => Err(format!("rpc: can't find method Plugin.{}", method)),
+            }
+        }
+    }
+
+    // The host's side of a net/rpc connection on one stream.
+    struct RpcClient {
+        stream: Stream,
+        encoder: Encoder,
+        decoder: Decoder,
+        seq: u64,
+    }
+
+    impl RpcClient {
+        fn new(stream: Stream) -> Self {
+            Self {
+                stream,
+                encoder: Encoder::new(),
+                decoder: Decoder::new(),
+                seq: 0,
+            }
+        }
+
+        // Returns the call's error, and its reply.
+        async fn call(&mut self, service_method: &str, args: Value) -> (String, Value) {
+            self.seq += 1;
+            let request = Value::Struct(
+                "Request".to_string(),
+                vec![
+                    (
+                        "ServiceMethod".to_string(),
+                        Value::String(service_method.to_string()),
+                    ),
+                    ("Seq".to_string(), Value::Uint(self.seq)),
+                ],
+            );
+            let mut message = self.encoder.encode(&request);
+            message.extend(self.encoder.encode(&args));
+            self.stream.write_all(&message).await.unwrap();
+
+            let response = self.decoder.decode(&mut self.stream).await.unwrap();
+            let reply = self.decoder.decode(&mut self.stream).await.unwrap();
+            assert_eq!(response.field("Seq"), Some(&Value::Uint(self.seq)));
+            match response.field("Error") {
+                Some(Value::String(error)) => (error.clone(), reply),
+                _ => (String::new(), reply),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_serve_conn() {
+        let (plugin_io, host_io) = tokio::io::duplex(64 * 1024);
+        let (stdio, mut stdout, _) = StdioSource::writers();
+        let (output, _) = stdio.capture().unwrap();
+        let (trigger, quit) = triggered::trigger();
+        let mut plugins = NetRpcPlugins::new();
+        plugins.insert("echo".to_string(), Arc::new(EchoService));
+        tokio::spawn(serve_conn(plugin_io, plugins, output, trigger));
+
+        let session = yamux::Connection::new(
+            host_io.compat(),
+            yamux::Config::default(),
+            yamux::Mode::Client,
+        );
+        let control = session.control();
+        tokio::spawn(async move {
+            let mut streams = Box::pin(yamux::into_stream(session));
+            while streams.next().await.is_some() {}
+        });
+
+        // The host opens the control stream, then stdout and stderr, like go-plugin's RPCClient.
+        // Go's yamux tells the other side about a stream as soon as it's opened.
+        let open = || {
+            let mut control = control.clone();
+            async move {
+                acknowledge(control.open_stream().await.unwrap().compat())
+                    .await
+                    .unwrap()
+            }
+        };
+        let mut rpc = RpcClient::new(open().await);
+        let mut host_stdout = open().await;
+        let _host_stderr = open().await;
+
+        let (error, _) = rpc.call("Control.Ping", Value::empty_struct()).await;
+        assert_eq!(error, "");
+
+        stdout.write_all(b"hello").unwrap();
+        let mut data = [0u8; 5];
+        host_stdout.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"hello");
+
+        // A dispensed plugin is served on the stream the host dials with its id.
+        let (error, _) = rpc
+            .call("Dispenser.Dispense", Value::String("unknown".to_string()))
+            .await;
+        assert_eq!(error, "unknown plugin type: unknown");
+        let (error, id) = rpc
+            .call("Dispenser.Dispense", Value::String("echo".to_string()))
+            .await;
+        assert_eq!(error, "");
+        let id = match id {
+            Value::Uint(id) => id as u32,
+            id => panic!("Dispense replied with {:?}", id),
+        };
+        let mut plugin = open().await;
+        plugin.write_u32_le(id).await.unwrap();
+        assert_eq!(plugin.read_u32_le().await.unwrap(), id);
+        let mut plugin = RpcClient::new(plugin);
+        let (error, reply) = plugin
+            .call("Plugin.Echo", Value::String("hi".to_string()))
+            .await;
+        assert_eq!(error, "");
+        assert_eq!(reply, Value::String("hi".to_string()));
+
+        assert!(!quit.is_triggered());
+        rpc.call("Control.Quit", Value::empty_struct()).await;
+        timeout(Duration::from_secs(5), quit).await.unwrap();
+    }
+}
@@ -0,0 +1,155 @@
+// A set of gRPC services served together as one plugin, like go-plugin's PluginSet.
+// Services of different types are boxed, and requests are routed to them by the service
+// name in the request path (/package.Service/Method).
+use super::error::Error;
+use super::handshake::ENV_PLUGIN_PROTOCOL_VERSIONS;
+use futures::future::{self, BoxFuture, FutureExt};
+use http::{Request, Response};
+use hyper::Body;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::task::{Context, Poll};
+use tonic::body::BoxBody;
+use tonic::codegen::empty_body;
+use tonic::transport::NamedService;
+use tower::util::BoxCloneService;
+use tower::{Service, ServiceExt};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+// Plugin sets, keyed by the app protocol version they implement.
+pub type VersionedPlugins = BTreeMap<u32, PluginSet>;
+
+#[derive(Clone, Default)]
+pub struct PluginSet {
+    services: HashMap<&'static str, BoxCloneService<Request<Body>, Response<BoxBody>, BoxError>>,
+}
+
+impl PluginSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_service<S>(mut self, svc: S) -> Self
+    where
+        S: Service<Request<Body>, Response = Response<BoxBody>>
+            + NamedService
+            + Clone
+            + Send
+            + 'static,
+        <S as Service<http::Request<hyper::Body>>>::Future: Send + 'static,
+        <S as Service<http::Request<hyper::Body>>>::Error:
+            Into<Box<dyn std::error::Error + Send + Sync>> + Send,
+    {
+        log::trace!("Adding service {} to plugin set", S::NAME);
+        if self.services.contains_key(S::NAME) {
+            log::warn!("Replacing service {} already in the plugin set", S::NAME);
+        }
+        self.services
+            .insert(S::NAME, BoxCloneService::new(svc.map_err(Into::into)));
+        self
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.services.keys().copied()
+    }
+}
+
+// The empty name makes tonic's router send this set every request no other service claimed,
+// so it must be the first service added to a router.
+impl NamedService for PluginSet {
+    const NAME: &'static str = "";
+}
+
+impl Service<Request<Body>> for PluginSet {
+    type Response = Response<BoxBody>;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        // path looks like /package.Service/Method
+        let name = req.uri().path().split('/').nth(1).unwrap_or_default();
+
+        match self.services.get(name) {
+            Some(svc) => svc.clone().oneshot(req).boxed(),
+            None => {
+                log::warn!(
+                    "No service in plugin set for request to {}",
+                    req.uri().path()
+                );
+                future::ok(
+                    Response::builder()
+                        .status(200)
+                        .header("grpc-status", "12")
+                        .header("content-type", "application/grpc")
+                        .body(empty_body())
+                        .unwrap(),
+                )
+                .boxed()
+            }
+        }
+    }
+}
+
+// Picks the highest version we have a plugin set for, which the host also listed
+// in PLUGIN_PROTOCOL_VERSIONS. Like go-plugin, falls back to our lowest version when
+// nothing matches, so the host can report the mismatch.
+// Copied from: https://github.com/hashicorp/go-plugin/blob/master/server.go#L139
+pub fn negotiate_protocol_version(versioned_plugins: &VersionedPlugins) -> Result<u32, Error> {
+    let host_versions = env::var(ENV_PLUGIN_PROTOCOL_VERSIONS).unwrap_or_default();
+    log::info!(
+        "Negotiating protocol version. Host supports: {:?}",
+        host_versions
+    );
+    negotiate(versioned_plugins.keys().copied(), host_versions.as_str())
+}
+
+fn negotiate(
+    versions: impl DoubleEndedIterator<Item = u32>,
+    host_versions: &str,
+) -> Result<u32, Error> {
+    let host_versions: Vec<u32> = host_versions
+        .split(',')
+        .filter(|v| !v.is_empty())
+        .filter_map(|v| match v.trim().parse() {
+            Ok(v) => Some(v),
+            Err(e) => {
+                log::error!("host sent invalid plugin version {:?}: {}", v, e);
+                None
+            }
+        })
+        .collect();
+
+    let mut negotiated = None;
+    for version in versions.rev() {
+        negotiated = Some(version);
+        if host_versions.contains(&version) {
+            break;
+        }
+    }
+
+    negotiated.ok_or(Error::NoPluginVersions)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert_matches::assert_matches;
+
+    #[test]
+    fn test_negotiate() {
+        let versions = [2u32, 3, 5];
+        assert_eq!(5, negotiate(versions.into_iter(), "1,5,3").unwrap());
+        assert_eq!(3, negotiate(versions.into_iter(), "3,4").unwrap());
+        assert_eq!(3, negotiate(versions.into_iter(), "x,3").unwrap());
+        // no overlap, or no host versions at all, picks our lowest version
+        assert_eq!(2, negotiate(versions.into_iter(), "4,6").unwrap());
+        assert_eq!(2, negotiate(versions.into_iter(), "").unwrap());
+
+        assert_matches!(negotiate([].into_iter(), "1"), Err(Error::NoPluginVersions));
+    }
+}
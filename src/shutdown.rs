@@ -0,0 +1,205 @@
+// Shutting down gracefully, for the main server and every brokered one. Once the host asks us to
+// shut down, servers stop accepting connections, and in-flight calls get until the grace period
+// is up to finish. Then the plugin's shutdown hooks run, and serving returns.
+use super::error::Error;
+use futures::future::{self, BoxFuture, Future};
+use std::io::Read;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+use tokio_util::task::TaskTracker;
+
+// Run once the plugin has shut down, e.g. to flush a database.
+pub type ShutdownHook = Box<dyn FnOnce() -> BoxFuture<'static, ()> + Send>;
+
+// Resolves when the plugin starts shutting down: when the host calls GRPCController.Shutdown
+// (or Control.Quit over net/rpc), when it closes our stdin if asked to watch it, or when serving
+// ends for any other reason.
+#[derive(Clone)]
+pub struct ShutdownToken {
+    listener: triggered::Listener,
+}
+
+impl ShutdownToken {
+    pub(crate) fn new(listener: triggered::Listener) -> Self {
+        Self { listener }
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.listener.is_triggered()
+    }
+}
+
+impl Future for ShutdownToken {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        Pin::new(&mut self.listener).poll(cx)
+    }
+}
+
+// For hosts that keep our stdin open while they're running, so it closing means they're gone.
+// Read on a thread of its own, since a blocked read would hold up the runtime shutting down.
+pub fn shutdown_on_stdin_close(trigger: triggered::Trigger) {
+    std::thread::spawn(move || {
+        let mut buf = [0u8; 1024];
+        let mut stdin = std::io::stdin();
+        loop {
+            match stdin.read(&mut buf) {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    log::error!("Unable to read stdin: {}", e);
+                    break;
+                }
+            }
+        }
+        log::info!("The host closed stdin. Shutting down...");
+        trigger.trigger();
+    });
+}
+
+// Like go-plugin, SIGINT is ignored when there's a host, since Ctrl-C in its terminal reaches
+// us too, and the host shuts us down itself. With no host to do that, SIGINT shuts us down.
+// SIGTERM always starts shutting down gracefully.
+// The handlers are registered before returning, so no signal is missed once serving starts.
+pub fn shutdown_on_signals(
+    trigger: triggered::Trigger,
+    ignore_interrupts: bool,
+) -> std::io::Result<()> {
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sigterm = signal(SignalKind::terminate())?;
+    tokio::spawn(async move {
+        let mut interrupts = 0usize;
+        loop {
+            tokio::select! {
+                Some(_) = sigint.recv() => {
+                    if ignore_interrupts {
+                        interrupts += 1;
+                        log::trace!("Received SIGINT, ignoring. Count: {}", interrupts);
+                        continue;
+                    }
+                    log::info!("Received SIGINT. Shutting down...");
+                }
+                Some(_) = sigterm.recv() => log::info!("Received SIGTERM. Shutting down..."),
+                else => break,
+            }
+            if trigger.is_triggered() {
+                log::warn!("Already shutting down, waiting for in-flight calls to finish.");
+            }
+            trigger.trigger();
+        }
+    });
+    Ok(())
+}
+
+#[derive(Clone)]
+pub struct Drain {
+    listener: triggered::Listener,
+    // None waits for in-flight calls however long they take.
+    grace_period: Option<Duration>,
+    // Brokered servers, waited for before the main server returns.
+    servers: TaskTracker,
+}
+
+impl Drain {
+    pub fn new(listener: triggered::Listener) -> Self {
+        Self {
+            listener,
+            grace_period: None,
+            servers: TaskTracker::new(),
+        }
+    }
+
+    pub fn with_grace_period(mut self, grace_period: Option<Duration>) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    pub fn grace_period(&self) -> Option<Duration> {
+        self.grace_period
+    }
+
+    // Stops servers from accepting connections.
+    pub fn listener(&self) -> triggered::Listener {
+        self.listener.clone()
+    }
+
+    // Resolves once shutdown has started and the grace period is up, or never without one.
+    pub fn deadline(&self) -> impl Future<Output = ()> + Send + 'static {
+        self.deadline_after(self.listener.clone())
+    }
+
+    // Resolves once `stop` has resolved and the grace period is up, or never without one.
+    fn deadline_after<F>(&self, stop: F) -> impl Future<Output = ()> + Send + 'static
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let grace_period = self.grace_period;
+        async move {
+            stop.await;
+            match grace_period {
+                Some(grace_period) => sleep(grace_period).await,
+                None => future::pending().await,
+            }
+        }
+    }
+
+    // Runs a brokered server, which stops accepting connections once stop resolves, until it's
+    // drained or the deadline passes. stop should resolve on listener too, so the server shuts
+    // down along with the plugin.
+    // What the server returns is returned through the JoinHandle, or Ok when the deadline passed.
+    pub fn spawn<F, S>(&self, name: String, server: F, stop: S) -> JoinHandle<Result<(), Error>>
+    where
+        F: Future<Output = Result<(), Error>> + Send + 'static,
+        S: Future<Output = ()> + Send + 'static,
+    {
+        let deadline = self.deadline_after(stop);
+        let grace_period = self.grace_period;
+        self.servers.spawn(async move {
+            tokio::select! {
+                result = server => result,
+                _ = deadline => {
+                    log::warn!(
+                        "{} had calls in flight {:?} after shutting down. Stopping it anyway.",
+                        name,
+                        grace_period
+                    );
+                    Ok(())
+                }
+            }
+        })
+    }
+
+    // Waits for every brokered server to finish draining.
+    pub async fn wait(&self) {
+        self.servers.close();
+        self.servers.wait().await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use nix::sys::signal::{raise, Signal};
+
+    #[tokio::test]
+    async fn test_shutdown_on_signals() {
+        let (trigger, listener) = triggered::trigger();
+        shutdown_on_signals(trigger, true).unwrap();
+
+        // With a host, SIGINT is the host's to act on.
+        raise(Signal::SIGINT).unwrap();
+        sleep(Duration::from_millis(100)).await;
+        assert!(!listener.is_triggered());
+
+        raise(Signal::SIGTERM).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), listener)
+            .await
+            .unwrap();
+    }
+}
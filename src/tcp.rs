@@ -0,0 +1,36 @@
+use super::error::Error;
+use super::unique_port::UniquePort;
+use async_stream::stream;
+use futures::Stream;
+use tokio::net::{TcpListener, TcpStream};
+
+// go-plugin plugins only ever listen on loopback.
+const LOOPBACK: &str = "127.0.0.1";
+
+// Binds the first port UniquePort vends which is actually bindable.
+// https://github.com/hashicorp/go-plugin/blob/master/server.go#L498
+pub async fn bind(unique_port: &mut UniquePort) -> Result<TcpListener, Error> {
+    loop {
+        let port = unique_port
+            .get_unused_port()
+            .ok_or(Error::NoTCPPortAvailable)?;
+
+        match TcpListener::bind((LOOPBACK, port)).await {
+            Ok(listener) => return Ok(listener),
+            Err(e) => log::trace!(
+                "Unable to bind {}:{}, trying another port: {}",
+                LOOPBACK,
+                port,
+                e
+            ),
+        }
+    }
+}
+
+pub fn incoming(listener: TcpListener) -> impl Stream<Item = Result<TcpStream, std::io::Error>> {
+    stream! {
+        loop {
+            yield listener.accept().await.map(|(st, _)| st);
+        }
+    }
+}
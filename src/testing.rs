@@ -0,0 +1,642 @@
+// Serves a plugin inside a test's own process, the way go-plugin's ServeTestConfig does, so a test
+// can talk to it as the host would without launching a binary. Nothing is read from the environment
+// or printed to stdout: the handshake is handed to the test, and the stdio service streams whatever
+// is written to the test's writers.
+// Modeled after: https://github.com/hashicorp/go-plugin/blob/master/testing.go
+use super::error::Error;
+use super::grpc_broker::dial;
+use super::grpc_broker_service::grpc_plugins::grpc_broker_client::GrpcBrokerClient;
+use super::grpc_controller::grpc_plugins::grpc_controller_client::GrpcControllerClient;
+use super::grpc_controller::grpc_plugins::Empty;
+use super::grpc_stdio::grpc_plugins::grpc_stdio_client::GrpcStdioClient;
+use super::grpc_stdio::{StdioSource, StdioWriter};
+use super::handshake::Handshake;
+use super::{
+    ConnInfo, GRpcBroker, HealthReporter, PluginSet, Server, ShutdownToken, StdioData, Transport,
+};
+use futures::future::Future;
+use futures::stream::Stream;
+use http::{Request, Response};
+use hyper::Body;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tonic::body::BoxBody;
+use tonic::transport::{Channel, NamedService};
+use tonic::Streaming;
+use tower::Service;
+
+pub struct TestServer {
+    server: Server,
+    handshake_receiver: oneshot::Receiver<Handshake>,
+    stdout: StdioWriter,
+    stderr: StdioWriter,
+}
+
+impl TestServer {
+    pub fn new(protocol_version: u32) -> Result<TestServer, Error> {
+        let (handshake_sender, handshake_receiver) = oneshot::channel();
+        let (stdio, stdout, stderr) = StdioSource::writers();
+        let server = Server::for_test(protocol_version, handshake_sender, stdio)?;
+
+        Ok(TestServer {
+            server,
+            handshake_receiver,
+            stdout,
+            stderr,
+        })
+    }
+
+    pub fn with_transport(mut self, transport: Transport) -> Self {
+        self.server.listen_config.transport = transport;
+        self
+    }
+
+    // See ServerBuilder::grace_period.
+    pub fn with_grace_period(mut self, grace_period: Duration) -> Self {
+        self.server.drain = self.server.drain.with_grace_period(Some(grace_period));
+        self
+    }
+
+    // See Server::on_shutdown.
+    pub fn on_shutdown<F, Fut>(&mut self, hook: F)
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.server.on_shutdown(hook);
+    }
+
+    pub fn shutdown_token(&self) -> ShutdownToken {
+        self.server.shutdown_token()
+    }
+
+    pub fn health_reporter(&self) -> HealthReporter {
+        self.server.health_reporter()
+    }
+
+    // The plugin's side of the broker, same as Server::grpc_broker.
+    pub async fn grpc_broker(&mut self) -> Result<GRpcBroker, Error> {
+        self.server.grpc_broker().await
+    }
+
+    // What's written here is streamed to the host as the plugin's stdout.
+    pub fn stdout(&self) -> StdioWriter {
+        self.stdout.clone()
+    }
+
+    // What's written here is streamed to the host as the plugin's stderr.
+    pub fn stderr(&self) -> StdioWriter {
+        self.stderr.clone()
+    }
+
+    // Starts serving the plugin in the background, and connects to it once it's listening.
+    pub async fn serve<S>(self, plugin: S) -> Result<TestClient, Error>
+    where
+        S: Service<Request<Body>, Response = Response<BoxBody>>
+            + NamedService
+            + Clone
+            + Send
+            + 'static,
+        <S as Service<http::Request<hyper::Body>>>::Future: Send + 'static,
+        <S as Service<http::Request<hyper::Body>>>::Error:
+            Into<Box<dyn std::error::Error + Send + Sync>> + Send,
+    {
+        self.start(|mut server| tokio::spawn(async move { server.serve(plugin).await }))
+            .await
+    }
+
+    // Same as serve, for Server::serve_plugins.
+    pub async fn serve_plugins(self, plugin_set: PluginSet) -> Result<TestClient, Error> {
+        self.start(|mut server| tokio::spawn(async move { server.serve_plugins(plugin_set).await }))
+            .await
+    }
+
+    async fn start(
+        self,
+        serve: impl FnOnce(Server) -> JoinHandle<Result<(), Error>>,
+    ) -> Result<TestClient, Error> {
+        let TestServer {
+            server,
+            handshake_receiver,
+            ..
+        } = self;
+
+        let mut serving = serve(server);
+
+        // The server failing to start drops the handshake sender, so this doesn't wait forever.
+        let handshake = tokio::select! {
+            handshake = handshake_receiver => handshake.ok(),
+            result = &mut serving => {
+                result.map_err(|e| Error::TestPluginStopped(e.to_string()))??;
+                None
+            }
+        };
+        let handshake = match handshake {
+            Some(handshake) => handshake,
+            None => {
+                return Err(Error::TestPluginStopped(
+                    "it returned before serving".to_string(),
+                ))
+            }
+        };
+        log::info!(
+            "Test plugin serving at {}:{}",
+            handshake.network,
+            handshake.address
+        );
+
+        let channel = dial(
+            ConnInfo {
+                service_id: 0,
+                network: handshake.network.clone(),
+                address: handshake.address.clone(),
+                knock: None,
+            },
+            None,
+        )
+        .await?;
+
+        Ok(TestClient {
+            handshake,
+            channel,
+            serving,
+        })
+    }
+}
+
+// The host's side of a plugin served by a TestServer.
+pub struct TestClient {
+    handshake: Handshake,
+    channel: Channel,
+    serving: JoinHandle<Result<(), Error>>,
+}
+
+impl TestClient {
+    pub fn channel(&self) -> Channel {
+        self.channel.clone()
+    }
+
+    pub fn handshake(&self) -> &Handshake {
+        &self.handshake
+    }
+
+    // The plugin's stdout and stderr, as go-plugin's GRPCStdio streams them to the host.
+    pub async fn stdio(&self) -> Result<Streaming<StdioData>, Error> {
+        let mut stdio = GrpcStdioClient::new(self.channel.clone());
+        let stream = stdio
+            .stream_stdio(())
+            .await
+            .map_err(|status| Error::Grpc(Box::new(status)))?;
+        Ok(stream.into_inner())
+    }
+
+    // Starts the host's side of the broker stream, sending the plugin our ConnInfo's and
+    // returning the plugin's.
+    pub async fn start_broker_stream<T>(&self, outgoing: T) -> Result<Streaming<ConnInfo>, Error>
+    where
+        T: Stream<Item = ConnInfo> + Send + Sync + 'static,
+    {
+        let mut broker = GrpcBrokerClient::new(self.channel.clone());
+        let stream = broker
+            .start_stream(outgoing)
+            .await
+            .map_err(|status| Error::Grpc(Box::new(status)))?;
+        Ok(stream.into_inner())
+    }
+
+    // Connects to a server the plugin brokered.
+    pub async fn dial(&self, conn_info: ConnInfo) -> Result<Channel, Error> {
+        dial(conn_info, None).await
+    }
+
+    // Shuts the plugin down through the GRPCController, and returns what serving it returned.
+    pub async fn shutdown(self) -> Result<(), Error> {
+        let mut controller = GrpcControllerClient::new(self.channel.clone());
+        controller
+            .shutdown(Empty {})
+            .await
+            .map_err(|status| Error::Grpc(Box::new(status)))?;
+        self.serving
+            .await
+            .map_err(|e| Error::TestPluginStopped(e.to_string()))?
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::grpc_stdio::grpc_plugins::stdio_data::Channel as StdioChannel;
+    use crate::{BrokeredChannel, HandshakeConfig, ServiceId, Status, PLUGIN_SERVICE_NAME};
+    use assert_matches::assert_matches;
+    use futures::future;
+    use http::uri::PathAndQuery;
+    use std::io::Write;
+    use tokio_stream::wrappers::UnboundedReceiverStream;
+    use tonic::codec::ProstCodec;
+    use tonic_health::proto::health_client::HealthClient;
+    use tonic_health::proto::HealthCheckRequest;
+
+    #[tokio::test]
+    async fn test_serve_in_process() {
+        let test_server = TestServer::new(1).unwrap();
+        let mut stdout = test_server.stdout();
+
+        let (_, health_service) = tonic_health::server::health_reporter();
+        let client = test_server.serve(health_service).await.unwrap();
+
+        // The server reports the plugin it serves as serving.
+        let mut health = HealthClient::new(client.channel());
+        let response = health
+            .check(HealthCheckRequest {
+                service: "grpc.health.v1.Health".to_string(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(response.into_inner().status, 1);
+
+        let mut stdio = client.stdio().await.unwrap();
+        stdout.write_all(b"hello").unwrap();
+        let data = stdio.message().await.unwrap().unwrap();
+        assert_eq!(data.channel, StdioChannel::Stdout as i32);
+        assert_eq!(data.data, b"hello");
+
+        client.shutdown().await.unwrap();
+    }
+
+    // Serves a plugin, and starts the host's side of the broker stream. The host tells the plugin
+    // about its services through the sender, which is dropped before shutting down, since
+    // go-plugin's host closes its side of the broker stream first.
+    async fn serve_with_broker() -> (
+        GRpcBroker,
+        TestClient,
+        tokio::sync::mpsc::UnboundedSender<ConnInfo>,
+        Streaming<ConnInfo>,
+    ) {
+        let mut test_server = TestServer::new(1).unwrap();
+        let broker = test_server.grpc_broker().await.unwrap();
+        let (_, health_service) = tonic_health::server::health_reporter();
+        let client = test_server.serve(health_service).await.unwrap();
+
+        let (outgoing, outgoing_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let incoming = client
+            .start_broker_stream(UnboundedReceiverStream::new(outgoing_receiver))
+            .await
+            .unwrap();
+        (broker, client, outgoing, incoming)
+    }
+
+    // A host service, served by the test plugin's own health service.
+    fn host_service(client: &TestClient, service_id: ServiceId) -> ConnInfo {
+        ConnInfo {
+            service_id,
+            network: client.handshake().network.clone(),
+            address: client.handshake().address.clone(),
+            knock: None,
+        }
+    }
+
+    fn check() -> HealthCheckRequest {
+        HealthCheckRequest {
+            service: "grpc.health.v1.Health".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dial_to_host_service_waits() {
+        let (broker, client, outgoing, _incoming) = serve_with_broker().await;
+
+        assert_matches!(
+            broker
+                .dial_to_host_service_within(7, Duration::from_millis(50), future::pending())
+                .await,
+            Err(Error::DialTimeout(7, _))
+        );
+        assert_matches!(
+            broker
+                .dial_to_host_service_within(
+                    7,
+                    Duration::from_secs(10),
+                    tokio::time::sleep(Duration::from_millis(50))
+                )
+                .await,
+            Err(Error::DialCancelled(7))
+        );
+
+        // A service the host tells us about while we're waiting is dialed right away.
+        let (dialed, _) = tokio::join!(broker.dial_to_host_service(8), async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            outgoing.send(host_service(&client, 8)).unwrap();
+        });
+        assert!(dialed.is_ok());
+
+        // It can be dialed again, until it's forgotten.
+        assert!(broker.dial_to_host_service(8).await.is_ok());
+        assert!(broker.forget_host_service(8).await);
+        assert_matches!(
+            broker
+                .dial_to_host_service_within(8, Duration::from_millis(50), future::pending())
+                .await,
+            Err(Error::DialTimeout(8, _))
+        );
+
+        drop(outgoing);
+        client.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dial_to_host_service_lazy() {
+        let (broker, client, outgoing, _incoming) = serve_with_broker().await;
+
+        // A lazy channel finds the service when it's first used.
+        let lazy = broker.dial_to_host_service_lazy(9).unwrap();
+        outgoing.send(host_service(&client, 9)).unwrap();
+        let response = HealthClient::new(lazy).check(check()).await.unwrap();
+        assert_eq!(response.into_inner().status, 1);
+
+        drop(outgoing);
+        client.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[allow(clippy::result_large_err)]
+    async fn test_dial_client_interceptors() {
+        let (broker, client, outgoing, _incoming) = serve_with_broker().await;
+        outgoing.send(host_service(&client, 9)).unwrap();
+
+        // Typed clients run the broker's interceptors on every call.
+        let mut health = broker
+            .clone()
+            .with_interceptor(|mut request| {
+                request
+                    .metadata_mut()
+                    .insert("x-plugin", "test".parse().unwrap());
+                Ok(request)
+            })
+            .dial_client::<HealthClient<BrokeredChannel>>(9)
+            .await
+            .unwrap();
+        assert!(health.check(check()).await.is_ok());
+        let mut denied = broker
+            .clone()
+            .with_interceptor(|_| Err(Status::permission_denied("not allowed")))
+            .dial_client::<HealthClient<BrokeredChannel>>(9)
+            .await
+            .unwrap();
+        assert_eq!(
+            denied.check(check()).await.unwrap_err().code(),
+            tonic::Code::PermissionDenied
+        );
+
+        drop(outgoing);
+        client.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_close_brokered_server() {
+        let mut test_server = TestServer::new(1).unwrap();
+        let broker = test_server.grpc_broker().await.unwrap();
+        // Every broker taken from the server shares its brokered servers.
+        let other_broker = test_server.grpc_broker().await.unwrap();
+        let (_, health_service) = tonic_health::server::health_reporter();
+        let client = test_server.serve(health_service.clone()).await.unwrap();
+
+        let (outgoing, outgoing_receiver) = tokio::sync::mpsc::unbounded_channel::<ConnInfo>();
+        let mut incoming = client
+            .start_broker_stream(UnboundedReceiverStream::new(outgoing_receiver))
+            .await
+            .unwrap();
+
+        let server = broker
+            .new_grpc_server(health_service.clone())
+            .await
+            .unwrap();
+        let conn_info = incoming.message().await.unwrap().unwrap();
+        assert_eq!(conn_info.service_id, server.service_id());
+        assert!(client.dial(conn_info.clone()).await.is_ok());
+        assert_matches!(
+            other_broker
+                .new_grpc_server_with_service_id(server.service_id(), health_service.clone())
+                .await
+                .err(),
+            Some(Error::ServiceIdInUse(_))
+        );
+
+        // Closing it removes its socket, and frees its id for another server.
+        let service_id = server.service_id();
+        server.close().await.unwrap();
+        assert!(!std::path::Path::new(&conn_info.address).exists());
+        assert!(client.dial(conn_info).await.is_err());
+        let server = other_broker
+            .new_grpc_server_with_service_id(service_id, health_service)
+            .await
+            .unwrap();
+        assert!(!server.is_finished());
+
+        // It stops along with the plugin.
+        drop(outgoing);
+        client.shutdown().await.unwrap();
+        assert!(server.is_finished());
+        server.wait().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drain_deadline() {
+        let (_, health_service) = tonic_health::server::health_reporter();
+        let client = TestServer::new(1)
+            .unwrap()
+            .with_grace_period(Duration::from_millis(100))
+            .serve(health_service)
+            .await
+            .unwrap();
+
+        // Watching health never ends, so only the deadline stops serving.
+        let mut health = HealthClient::new(client.channel());
+        let _watch = health
+            .watch(HealthCheckRequest {
+                service: "grpc.health.v1.Health".to_string(),
+            })
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(5), client.shutdown())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_hooks() {
+        let mut test_server = TestServer::new(1).unwrap();
+        let token = test_server.shutdown_token();
+        let (hook_sender, mut hook_receiver) = oneshot::channel();
+        let hook_token = token.clone();
+        test_server.on_shutdown(move || async move {
+            // Hooks run after shutting down has started.
+            let _ = hook_sender.send(hook_token.is_shutting_down());
+        });
+
+        let (_, health_service) = tonic_health::server::health_reporter();
+        let client = test_server.serve(health_service).await.unwrap();
+        assert!(!token.is_shutting_down());
+        assert!(hook_receiver.try_recv().is_err());
+
+        client.shutdown().await.unwrap();
+        token.await;
+        assert!(hook_receiver.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_hooks_after_failing_to_serve() {
+        // No host set the magic cookie, so serving fails before it starts.
+        let mut server = Server::without_env(
+            1,
+            HandshakeConfig {
+                magic_cookie_key: "GRR_PLUGIN_TEST_UNSET_COOKIE".to_string(),
+                magic_cookie_value: "cookie".to_string(),
+            },
+        )
+        .unwrap();
+        let token = server.shutdown_token();
+        let (hook_sender, hook_receiver) = oneshot::channel();
+        server.on_shutdown(move || async move {
+            let _ = hook_sender.send(());
+        });
+
+        let (_, health_service) = tonic_health::server::health_reporter();
+        assert_matches!(
+            server.serve(health_service).await,
+            Err(Error::GRPCHandshakeMagicCookieValueMismatch)
+        );
+        assert!(token.is_shutting_down());
+        hook_receiver.await.unwrap();
+    }
+
+    // Answers every call with its name, in the x-service metadata.
+    #[derive(Clone)]
+    struct NameReply(&'static str);
+
+    impl tonic::server::UnaryService<()> for NameReply {
+        type Response = ();
+        type Future = future::Ready<Result<tonic::Response<()>, Status>>;
+
+        fn call(&mut self, _: tonic::Request<()>) -> Self::Future {
+            let mut response = tonic::Response::new(());
+            response
+                .metadata_mut()
+                .insert("x-service", self.0.parse().unwrap());
+            future::ready(Ok(response))
+        }
+    }
+
+    macro_rules! name_service {
+        ($service:ident, $name:literal) => {
+            #[derive(Clone)]
+            struct $service;
+
+            impl NamedService for $service {
+                const NAME: &'static str = $name;
+            }
+
+            impl Service<Request<Body>> for $service {
+                type Response = Response<BoxBody>;
+                type Error = std::convert::Infallible;
+                type Future = future::BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+                fn poll_ready(
+                    &mut self,
+                    _cx: &mut std::task::Context<'_>,
+                ) -> std::task::Poll<Result<(), Self::Error>> {
+                    std::task::Poll::Ready(Ok(()))
+                }
+
+                fn call(&mut self, req: Request<Body>) -> Self::Future {
+                    Box::pin(async move {
+                        let mut grpc = tonic::server::Grpc::new(ProstCodec::<(), ()>::default());
+                        Ok(grpc.unary(NameReply($name), req).await)
+                    })
+                }
+            }
+        };
+    }
+
+    name_service!(FirstService, "test.First");
+    name_service!(SecondService, "test.Second");
+
+    #[tokio::test]
+    async fn test_serve_plugin_set_routes() {
+        let client = TestServer::new(1)
+            .unwrap()
+            .serve_plugins(
+                PluginSet::new()
+                    .add_service(FirstService)
+                    .add_service(SecondService),
+            )
+            .await
+            .unwrap();
+
+        let call = |path: &'static str| {
+            let mut grpc = tonic::client::Grpc::new(client.channel());
+            async move {
+                grpc.ready().await.unwrap();
+                grpc.unary(
+                    tonic::Request::new(()),
+                    PathAndQuery::from_static(path),
+                    ProstCodec::<(), ()>::default(),
+                )
+                .await
+                .map(|response| response.metadata().get("x-service").cloned())
+            }
+        };
+
+        // Each call reaches the service it names.
+        assert_eq!(
+            call("/test.First/Call").await.unwrap().unwrap(),
+            "test.First"
+        );
+        assert_eq!(
+            call("/test.Second/Call").await.unwrap().unwrap(),
+            "test.Second"
+        );
+        assert_eq!(
+            call("/test.Unknown/Call").await.unwrap_err().code(),
+            tonic::Code::Unimplemented
+        );
+
+        client.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_serve_plugins_health() {
+        let (_, health_service) = tonic_health::server::health_reporter();
+        let test_server = TestServer::new(1).unwrap();
+        let reporter = test_server.health_reporter();
+        reporter.set_not_serving(PLUGIN_SERVICE_NAME).await;
+        let client = test_server
+            .serve_plugins(PluginSet::new().add_service(health_service))
+            .await
+            .unwrap();
+
+        let health = HealthClient::new(client.channel());
+        let check = |service: &str| {
+            let request = HealthCheckRequest {
+                service: service.to_string(),
+            };
+            let mut health = health.clone();
+            async move { health.check(request).await.map(|r| r.into_inner().status) }
+        };
+
+        // Every service in the set, and the plugin as a whole, is reported as serving, unless
+        // the plugin reported otherwise.
+        assert_eq!(check("grpc.health.v1.Health").await.unwrap(), 1);
+        assert_eq!(check("").await.unwrap(), 1);
+        assert_eq!(check(PLUGIN_SERVICE_NAME).await.unwrap(), 2);
+        assert!(check("not.in.the.Set").await.is_err());
+
+        reporter.set_serving(PLUGIN_SERVICE_NAME).await;
+        assert_eq!(check(PLUGIN_SERVICE_NAME).await.unwrap(), 1);
+        reporter.set_not_serving("").await;
+        assert_eq!(check("").await.unwrap(), 2);
+
+        client.shutdown().await.unwrap();
+    }
+}
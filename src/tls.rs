@@ -0,0 +1,351 @@
+// go-plugin's AutoMTLS: when the host sets PLUGIN_CLIENT_CERT, the plugin generates an ephemeral
+// certificate, serves TLS that only accepts the host's certificate, and sends its own certificate
+// back to the host in the handshake.
+// Copied from: https://github.com/hashicorp/go-plugin/blob/master/server.go and mtls.go
+//
+// This uses OpenSSL rather than tonic's rustls-based TLS, because go-plugin hosts generate
+// P-521 certificates, which rustls/ring can't verify.
+use super::error::Error;
+use async_stream::stream;
+use futures::{Stream, StreamExt};
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::ssl::{
+    select_next_proto, AlpnError, Ssl, SslAcceptor, SslConnector, SslMethod, SslVerifyMode,
+};
+use openssl::x509::extension::{
+    BasicConstraints, ExtendedKeyUsage, KeyUsage, SubjectAlternativeName,
+};
+use openssl::x509::{X509NameBuilder, X509};
+use std::env;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc::unbounded_channel;
+use tokio_openssl::SslStream;
+use tonic::transport::server::Connected;
+
+pub const ENV_PLUGIN_CLIENT_CERT: &str = "PLUGIN_CLIENT_CERT";
+
+// Both sides of go-plugin's AutoMTLS name each other "localhost".
+const SERVER_NAME: &str = "localhost";
+
+// gRPC requires HTTP/2 to be negotiated over ALPN.
+const ALPN_H2: &[u8] = b"\x02h2";
+
+// Same as go-plugin's generateCert: 262980 hours
+const CERT_VALIDITY_DAYS: u32 = 10957;
+
+#[derive(Clone)]
+pub struct AutoMtls {
+    acceptor: SslAcceptor,
+    connector: SslConnector,
+    server_cert: String,
+}
+
+impl AutoMtls {
+    // Returns None when the host hasn't asked for AutoMTLS.
+    pub fn from_env() -> Result<Option<AutoMtls>, Error> {
+        match env::var(ENV_PLUGIN_CLIENT_CERT) {
+            Ok(client_cert) if !client_cert.is_empty() => {
+                log::info!("configuring server automatic mTLS");
+                Ok(Some(Self::new(client_cert.as_bytes())?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    pub fn new(client_cert_pem: &[u8]) -> Result<AutoMtls, Error> {
+        let (cert, key) = generate_cert()?;
+        Self::with_cert(client_cert_pem, cert, key)
+    }
+
+    fn with_cert(
+        client_cert_pem: &[u8],
+        cert: X509,
+        key: PKey<Private>,
+    ) -> Result<AutoMtls, Error> {
+        let client_certs = X509::stack_from_pem(client_cert_pem)?;
+        if client_certs.is_empty() {
+            return Err(Error::Tls(format!(
+                "{} was provided but contained no PEM certificates",
+                ENV_PLUGIN_CLIENT_CERT
+            )));
+        }
+
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())?;
+        acceptor.set_private_key(&key)?;
+        acceptor.set_certificate(&cert)?;
+        acceptor.check_private_key()?;
+        for client_cert in client_certs.iter() {
+            acceptor.cert_store_mut().add_cert(client_cert.clone())?;
+        }
+        acceptor.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        acceptor.set_alpn_select_callback(|_, client_protos| {
+            select_next_proto(ALPN_H2, client_protos).ok_or(AlpnError::NOACK)
+        });
+
+        // Used when dialing servers the host brokers to us, which present the host's certificate.
+        let mut connector = SslConnector::builder(SslMethod::tls_client())?;
+        connector.set_private_key(&key)?;
+        connector.set_certificate(&cert)?;
+        for client_cert in client_certs {
+            connector.cert_store_mut().add_cert(client_cert)?;
+        }
+        connector.set_alpn_protos(ALPN_H2)?;
+
+        // We send back the raw leaf cert data for the client rather than the
+        // PEM, since the protocol can't handle newlines.
+        let server_cert = base64::encode_config(cert.to_der()?, base64::STANDARD_NO_PAD);
+
+        Ok(AutoMtls {
+            acceptor: acceptor.build(),
+            connector: connector.build(),
+            server_cert,
+        })
+    }
+
+    // The base64 DER of our certificate, for the sixth field of the handshake.
+    pub fn server_cert(&self) -> &str {
+        self.server_cert.as_str()
+    }
+
+    pub async fn accept<IO>(&self, io: IO) -> Result<MaybeTlsStream<IO>, std::io::Error>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin,
+    {
+        let ssl = Ssl::new(self.acceptor.context()).map_err(std::io::Error::other)?;
+        let mut tls_stream = Box::pin(SslStream::new(ssl, io).map_err(std::io::Error::other)?);
+        tls_stream
+            .as_mut()
+            .accept()
+            .await
+            .map_err(std::io::Error::other)?;
+        Ok(MaybeTlsStream::Tls(tls_stream))
+    }
+
+    pub async fn connect<IO>(&self, io: IO) -> Result<MaybeTlsStream<IO>, std::io::Error>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin,
+    {
+        let ssl = self
+            .connector
+            .configure()
+            .and_then(|c| c.into_ssl(SERVER_NAME))
+            .map_err(std::io::Error::other)?;
+        let mut tls_stream = Box::pin(SslStream::new(ssl, io).map_err(std::io::Error::other)?);
+        tls_stream
+            .as_mut()
+            .connect()
+            .await
+            .map_err(std::io::Error::other)?;
+        Ok(MaybeTlsStream::Tls(tls_stream))
+    }
+}
+
+// Wraps an incoming stream of connections in TLS when AutoMTLS is on.
+// Handshakes happen in their own tasks so one slow or failed client can't block, or end,
+// the server's accept loop.
+pub fn incoming<S, IO>(
+    incoming: S,
+    auto_mtls: Option<AutoMtls>,
+) -> impl Stream<Item = Result<MaybeTlsStream<IO>, std::io::Error>>
+where
+    S: Stream<Item = Result<IO, std::io::Error>> + Send + 'static,
+    IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    stream! {
+        let mut incoming = Box::pin(incoming);
+        match auto_mtls {
+            None => {
+                while let Some(conn) = incoming.next().await {
+                    yield conn.map(MaybeTlsStream::Plain);
+                }
+            }
+            Some(auto_mtls) => {
+                // The listener is polled here rather than in a task of its own, so it's dropped,
+                // and stops taking connections, as soon as the server stops reading from us.
+                let (handshaken_sender, mut handshaken_receiver) = unbounded_channel();
+                loop {
+                    tokio::select! {
+                        conn = incoming.next() => {
+                            let io = match conn {
+                                Some(Ok(io)) => io,
+                                Some(Err(e)) => {
+                                    yield Err(e);
+                                    continue;
+                                }
+                                None => break,
+                            };
+
+                            let auto_mtls = auto_mtls.clone();
+                            let handshaken_sender = handshaken_sender.clone();
+                            tokio::spawn(async move {
+                                match auto_mtls.accept(io).await {
+                                    Ok(tls_stream) => {
+                                        let _ = handshaken_sender.send(tls_stream);
+                                    }
+                                    Err(e) => log::warn!("TLS handshake with incoming connection failed: {}", e),
+                                }
+                            });
+                        }
+                        Some(tls_stream) = handshaken_receiver.recv() => yield Ok(tls_stream),
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub enum MaybeTlsStream<IO> {
+    Plain(IO),
+    Tls(Pin<Box<SslStream<IO>>>),
+}
+
+impl<IO: Connected> Connected for MaybeTlsStream<IO> {
+    type ConnectInfo = IO::ConnectInfo;
+
+    fn connect_info(&self) -> Self::ConnectInfo {
+        match self {
+            MaybeTlsStream::Plain(io) => io.connect_info(),
+            MaybeTlsStream::Tls(tls_stream) => tls_stream.get_ref().connect_info(),
+        }
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> AsyncRead for MaybeTlsStream<IO> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(io) => Pin::new(io).poll_read(cx, buf),
+            MaybeTlsStream::Tls(tls_stream) => tls_stream.as_mut().poll_read(cx, buf),
+        }
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> AsyncWrite for MaybeTlsStream<IO> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(io) => Pin::new(io).poll_write(cx, buf),
+            MaybeTlsStream::Tls(tls_stream) => tls_stream.as_mut().poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(io) => Pin::new(io).poll_flush(cx),
+            MaybeTlsStream::Tls(tls_stream) => tls_stream.as_mut().poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(io) => Pin::new(io).poll_shutdown(cx),
+            MaybeTlsStream::Tls(tls_stream) => tls_stream.as_mut().poll_shutdown(cx),
+        }
+    }
+}
+
+// Copied from: https://github.com/hashicorp/go-plugin/blob/master/mtls.go
+pub(crate) fn generate_cert() -> Result<(X509, PKey<Private>), ErrorStack> {
+    let group = EcGroup::from_curve_name(Nid::SECP521R1)?;
+    let key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+
+    let mut serial_number = BigNum::new()?;
+    serial_number.rand(128, MsbOption::MAYBE_ZERO, false)?;
+
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, SERVER_NAME)?;
+    name.append_entry_by_nid(Nid::ORGANIZATIONNAME, "HashiCorp")?;
+    let name = name.build();
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default();
+
+    let mut builder = X509::builder()?;
+    builder.set_version(2)?;
+    builder.set_serial_number(serial_number.to_asn1_integer()?.as_ref())?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(&name)?;
+    builder.set_pubkey(&key)?;
+    builder.set_not_before(Asn1Time::from_unix(now - 30)?.as_ref())?;
+    builder.set_not_after(Asn1Time::days_from_now(CERT_VALIDITY_DAYS)?.as_ref())?;
+    builder.append_extension(BasicConstraints::new().critical().ca().build()?)?;
+    builder.append_extension(
+        KeyUsage::new()
+            .digital_signature()
+            .key_encipherment()
+            .key_agreement()
+            .key_cert_sign()
+            .build()?,
+    )?;
+    builder.append_extension(
+        ExtendedKeyUsage::new()
+            .client_auth()
+            .server_auth()
+            .build()?,
+    )?;
+    let san = SubjectAlternativeName::new()
+        .dns(SERVER_NAME)
+        .build(&builder.x509v3_context(None, None))?;
+    builder.append_extension(san)?;
+    builder.sign(&key, MessageDigest::sha512())?;
+
+    Ok((builder.build(), key))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_auto_mtls_handshake() {
+        let (host_cert, host_key) = generate_cert().unwrap();
+        let (plugin_cert, plugin_key) = generate_cert().unwrap();
+        let host = AutoMtls::with_cert(&plugin_cert.to_pem().unwrap(), host_cert.clone(), host_key)
+            .unwrap();
+        let plugin = AutoMtls::with_cert(
+            &host_cert.to_pem().unwrap(),
+            plugin_cert.clone(),
+            plugin_key,
+        )
+        .unwrap();
+
+        let advertised =
+            base64::decode_config(plugin.server_cert(), base64::STANDARD_NO_PAD).unwrap();
+        assert_eq!(plugin_cert.to_der().unwrap(), advertised);
+
+        let (plugin_io, host_io) = duplex(4096);
+        let (accepted, connected) = tokio::join!(plugin.accept(plugin_io), host.connect(host_io));
+        let mut accepted = accepted.unwrap();
+        let mut connected = connected.unwrap();
+
+        connected.write_all(b"ping").await.unwrap();
+        connected.flush().await.unwrap();
+        let mut buf = [0u8; 4];
+        accepted.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"ping", &buf);
+
+        // Someone presenting a certificate other than the host's is turned away.
+        let stranger = AutoMtls::new(&plugin_cert.to_pem().unwrap()).unwrap();
+        let (plugin_io, stranger_io) = duplex(4096);
+        let (accepted, _) = tokio::join!(plugin.accept(plugin_io), stranger.connect(stranger_io));
+        assert!(accepted.is_err());
+    }
+}
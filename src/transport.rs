@@ -0,0 +1,158 @@
+// Where a plugin listens: a unix socket (the default, like go-plugin on unix), or a tcp port on loopback.
+use super::error::Error;
+use super::tcp;
+use super::unique_port::UniquePort;
+use super::unix::{self, SocketConfig, TempSocket, UdsConnectInfo};
+use async_stream::stream;
+use futures::stream::{BoxStream, StreamExt};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_util::compat::Compat;
+use tonic::transport::server::{Connected, TcpConnectInfo};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Transport {
+    // Falls back to Tcp when a unix socket can't be opened.
+    #[default]
+    Unix,
+    Tcp,
+}
+
+// How to listen, for the main server and every brokered one.
+#[derive(Clone, Debug, Default)]
+pub struct ListenConfig {
+    pub transport: Transport,
+    pub unix: SocketConfig,
+}
+
+pub struct Listener {
+    pub network: String,
+    pub address: String,
+    pub incoming: BoxStream<'static, Result<Connection, std::io::Error>>,
+}
+
+pub async fn listen(
+    config: &ListenConfig,
+    unique_port: &mut UniquePort,
+) -> Result<Listener, Error> {
+    match config.transport {
+        Transport::Tcp => listen_tcp(unique_port).await,
+        Transport::Unix => match listen_unix(&config.unix).await {
+            Ok(listener) => Ok(listener),
+            Err(e) => {
+                log::warn!(
+                    "Unable to listen on a unix socket. Falling back to tcp. Error: {}",
+                    e
+                );
+                listen_tcp(unique_port).await
+            }
+        },
+    }
+}
+
+async fn listen_unix(config: &SocketConfig) -> Result<Listener, Error> {
+    let temp_socket = TempSocket::new(config)?;
+    let socket_path = temp_socket.socket_filename()?;
+    let mut incoming = Box::pin(unix::incoming_from_path(socket_path.as_str(), config).await?);
+    log::trace!("Listening on unix socket: {}", socket_path);
+
+    Ok(Listener {
+        network: "unix".to_string(),
+        address: socket_path,
+        incoming: stream! {
+            // own the temp socket for as long as we're accepting on it, so it doesn't get deleted.
+            let _temp_socket = temp_socket;
+            while let Some(conn) = incoming.next().await {
+                yield conn.map(Connection::Unix);
+            }
+        }
+        .boxed(),
+    })
+}
+
+async fn listen_tcp(unique_port: &mut UniquePort) -> Result<Listener, Error> {
+    let listener = tcp::bind(unique_port).await?;
+    let address = listener.local_addr()?.to_string();
+    log::trace!("Listening on tcp address: {}", address);
+
+    Ok(Listener {
+        network: "tcp".to_string(),
+        address,
+        incoming: tcp::incoming(listener)
+            .map(|conn| conn.map(Connection::Tcp))
+            .boxed(),
+    })
+}
+
+pub enum Connection {
+    Unix(unix::UnixStream),
+    Tcp(TcpStream),
+    // A stream in the yamux session with the host, under PLUGIN_MULTIPLEX_GRPC
+    Mux(Compat<yamux::Stream>),
+}
+
+// Available to services from the request's extensions.
+#[derive(Clone, Debug)]
+pub enum ConnectInfo {
+    Unix(UdsConnectInfo),
+    Tcp(TcpConnectInfo),
+    Mux,
+}
+
+impl Connected for Connection {
+    type ConnectInfo = ConnectInfo;
+
+    fn connect_info(&self) -> Self::ConnectInfo {
+        match self {
+            Connection::Unix(st) => ConnectInfo::Unix(st.connect_info()),
+            Connection::Tcp(st) => ConnectInfo::Tcp(st.connect_info()),
+            Connection::Mux(_) => ConnectInfo::Mux,
+        }
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Connection::Unix(st) => Pin::new(st).poll_read(cx, buf),
+            Connection::Tcp(st) => Pin::new(st).poll_read(cx, buf),
+            Connection::Mux(st) => Pin::new(st).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Unix(st) => Pin::new(st).poll_write(cx, buf),
+            Connection::Tcp(st) => Pin::new(st).poll_write(cx, buf),
+            Connection::Mux(st) => Pin::new(st).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Connection::Unix(st) => Pin::new(st).poll_flush(cx),
+            Connection::Tcp(st) => Pin::new(st).poll_flush(cx),
+            Connection::Mux(st) => Pin::new(st).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Connection::Unix(st) => Pin::new(st).poll_shutdown(cx),
+            Connection::Tcp(st) => Pin::new(st).poll_shutdown(cx),
+            Connection::Mux(st) => Pin::new(st).poll_shutdown(cx),
+        }
+    }
+}
@@ -1,17 +1,70 @@
+use super::error::Error;
 use portpicker::Port;
+use std::env;
 
+// The host limits which ports a plugin may listen on through these.
+// https://github.com/hashicorp/go-plugin/blob/master/server.go#L498
+pub const ENV_PLUGIN_MIN_PORT: &str = "PLUGIN_MIN_PORT";
+pub const ENV_PLUGIN_MAX_PORT: &str = "PLUGIN_MAX_PORT";
+
+#[derive(Debug)]
 pub struct UniquePort {
     vended_ports: Vec<Port>,
+    // When set, ports are only vended from this inclusive range.
+    range: Option<(Port, Port)>,
 }
 
 impl UniquePort {
     pub fn new() -> Self {
         Self {
             vended_ports: vec![],
+            range: None,
+        }
+    }
+
+    pub fn with_range(min_port: Port, max_port: Port) -> Result<Self, Error> {
+        if min_port > max_port {
+            return Err(Error::InvalidPortRange(format!(
+                "{} value of {} is greater than {} value of {}",
+                ENV_PLUGIN_MIN_PORT, min_port, ENV_PLUGIN_MAX_PORT, max_port
+            )));
+        }
+
+        Ok(Self {
+            vended_ports: vec![],
+            range: Some((min_port, max_port)),
+        })
+    }
+
+    // Honors PLUGIN_MIN_PORT and PLUGIN_MAX_PORT if the host set either of them.
+    pub fn from_env() -> Result<Self, Error> {
+        let min_port = port_from_env(ENV_PLUGIN_MIN_PORT)?;
+        let max_port = port_from_env(ENV_PLUGIN_MAX_PORT)?;
+
+        match (min_port, max_port) {
+            (None, None) => Ok(Self::new()),
+            (min_port, max_port) => {
+                Self::with_range(min_port.unwrap_or(Port::MIN), max_port.unwrap_or(Port::MAX))
+            }
         }
     }
 
     pub fn get_unused_port(&mut self) -> Option<Port> {
+        if let Some((min_port, max_port)) = self.range {
+            let port = (min_port..=max_port)
+                .find(|p| !self.vended_ports.contains(p) && portpicker::is_free_tcp(*p));
+            log::trace!(
+                "Vending port: {:?} from range {}-{}",
+                port,
+                min_port,
+                max_port
+            );
+            if let Some(p) = port {
+                self.vended_ports.push(p);
+            }
+            return port;
+        }
+
         let mut counter = 0;
 
         loop {
@@ -36,6 +89,12 @@ impl UniquePort {
             }
         }
     }
+
+    // Lets a port be vended again, once whatever it was vended for has stopped listening on it.
+    pub fn release(&mut self, port: Port) {
+        log::trace!("Releasing port: {}", port);
+        self.vended_ports.retain(|p| *p != port);
+    }
 }
 
 impl Default for UniquePort {
@@ -43,3 +102,47 @@ impl Default for UniquePort {
         Self::new()
     }
 }
+
+fn port_from_env(var: &str) -> Result<Option<Port>, Error> {
+    match env::var(var) {
+        Ok(value) if !value.is_empty() => value.parse().map(Some).map_err(|e| {
+            Error::InvalidPortRange(format!(
+                "Couldn't get value from {}: {:?}: {}",
+                var, value, e
+            ))
+        }),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert_matches::assert_matches;
+
+    #[test]
+    fn test_port_range() {
+        // Hold the first port, so it isn't free to vend.
+        let mut probe = UniquePort::new();
+        let held_port = probe.get_unused_port().unwrap();
+        let _held = std::net::TcpListener::bind(("127.0.0.1", held_port)).unwrap();
+
+        let mut u = UniquePort::with_range(held_port, held_port + 2).unwrap();
+        let vended = [
+            u.get_unused_port(),
+            u.get_unused_port(),
+            u.get_unused_port(),
+        ];
+        assert!(!vended.contains(&Some(held_port)));
+        assert_eq!(None, vended[2]);
+
+        // Released ports can be vended again.
+        u.release(vended[0].unwrap());
+        assert_eq!(vended[0], u.get_unused_port());
+
+        assert_matches!(
+            UniquePort::with_range(2, 1),
+            Err(Error::InvalidPortRange(_))
+        );
+    }
+}
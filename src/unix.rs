@@ -1,11 +1,15 @@
-use anyhow::anyhow;
 use async_stream::stream;
 use futures::Stream;
 use futures::TryFutureExt;
+use nix::unistd::{chown, Gid, Group};
 use tempfile::{tempdir, TempDir};
 use tokio::net::UnixListener;
 
 use std::{
+    env,
+    fs::{set_permissions, Permissions},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
@@ -16,32 +20,94 @@ use tonic::transport::server::Connected;
 
 use super::error::Error;
 
-const SOCKET_FILENAME: &str = "landslide_jsonrpc.sock";
+const SOCKET_FILENAME: &str = "plugin.sock";
+
+// Hosts that share sockets with sandboxed plugins tell us where to put them, and which group
+// should be able to connect to them.
+// https://github.com/hashicorp/go-plugin/blob/master/server.go#L556
+pub const ENV_PLUGIN_UNIX_SOCKET_DIR: &str = "PLUGIN_UNIX_SOCKET_DIR";
+pub const ENV_PLUGIN_UNIX_SOCKET_GROUP: &str = "PLUGIN_UNIX_SOCKET_GROUP";
+
+// Group members need to traverse the directory, and read/write the socket.
+const SOCKET_DIR_GROUP_MODE: u32 = 0o750;
+const SOCKET_GROUP_MODE: u32 = 0o660;
+
+// Where sockets are created, and which group can connect to them. By default, in a new
+// temp directory that only we can use.
+#[derive(Clone, Debug, Default)]
+pub struct SocketConfig {
+    dir: Option<PathBuf>,
+    group: Option<Gid>,
+}
+
+impl SocketConfig {
+    pub fn from_env() -> Result<SocketConfig, Error> {
+        let dir = match env::var_os(ENV_PLUGIN_UNIX_SOCKET_DIR) {
+            Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+            _ => None,
+        };
+        let group = match env::var(ENV_PLUGIN_UNIX_SOCKET_GROUP) {
+            Ok(group) if !group.is_empty() => Some(resolve_group(group.as_str())?),
+            _ => None,
+        };
+        Ok(SocketConfig { dir, group })
+    }
+
+    pub fn with_dir(mut self, dir: PathBuf) -> Self {
+        self.dir = Some(dir);
+        self
+    }
+
+    // The group may be given as a gid or a name.
+    pub fn with_group(mut self, group: &str) -> Result<Self, Error> {
+        self.group = Some(resolve_group(group)?);
+        Ok(self)
+    }
+
+    pub fn dir(&self) -> Option<&Path> {
+        self.dir.as_deref()
+    }
+}
 
 //own this so it doesn't go out of scope and get deleted
 pub struct TempSocket(TempDir);
 impl TempSocket {
-    pub fn new() -> Result<TempSocket, Error> {
-        Ok(Self(tempdir()?))
+    pub fn new(config: &SocketConfig) -> Result<TempSocket, Error> {
+        let temp_dir = match &config.dir {
+            Some(dir) => {
+                log::trace!("Creating temp socket directory in {:?}", dir);
+                tempfile::Builder::new().prefix("plugin").tempdir_in(dir)?
+            }
+            None => tempdir()?,
+        };
+
+        if let Some(gid) = config.group {
+            set_group_accessible(temp_dir.path(), gid, SOCKET_DIR_GROUP_MODE)?;
+        }
+
+        Ok(Self(temp_dir))
     }
 
     pub fn socket_filename(&self) -> Result<String, Error> {
-        let socket_path_buf = self.0.path().join(SOCKET_FILENAME);
-        let socket_path = String::from(socket_path_buf.to_str().ok_or_else(|| {
-            anyhow!(
-                "Unable to convert PathBuf {:?} to a String.",
-                socket_path_buf
-            )
-        })?);
-        Ok(socket_path)
+        let socket_path = self.0.path().join(SOCKET_FILENAME);
+        match socket_path.to_str() {
+            Some(socket_path) => Ok(socket_path.to_string()),
+            None => Err(Error::SocketPath(socket_path)),
+        }
     }
 }
 
 pub async fn incoming_from_path(
     path: &str,
+    config: &SocketConfig,
 ) -> Result<impl Stream<Item = Result<UnixStream, std::io::Error>>, Error> {
     let uds = UnixListener::bind(path)?;
 
+    // By default, unix sockets are only writable by the owner.
+    if let Some(gid) = config.group {
+        set_group_accessible(Path::new(path), gid, SOCKET_GROUP_MODE)?;
+    }
+
     Ok(stream! {
         loop {
             let item = uds.accept().map_ok(|(st, _)| UnixStream(st)).await;
@@ -51,6 +117,36 @@ pub async fn incoming_from_path(
     })
 }
 
+fn resolve_group(group: &str) -> Result<Gid, Error> {
+    if let Ok(gid) = group.parse() {
+        return Ok(Gid::from_raw(gid));
+    }
+
+    match Group::from_name(group) {
+        Ok(Some(g)) => Ok(g.gid),
+        Ok(None) => Err(Error::UnixSocketGroup(format!(
+            "failed to find gid from {:?}: no such group",
+            group
+        ))),
+        Err(e) => Err(Error::UnixSocketGroup(format!(
+            "failed to find gid from {:?}: {}",
+            group, e
+        ))),
+    }
+}
+
+fn set_group_accessible(path: &Path, gid: Gid, mode: u32) -> Result<(), Error> {
+    log::trace!(
+        "Setting group of {:?} to {} with mode {:o}",
+        path,
+        gid,
+        mode
+    );
+    chown(path, None, Some(gid)).map_err(std::io::Error::from)?;
+    set_permissions(path, Permissions::from_mode(mode))?;
+    Ok(())
+}
+
 #[derive(Debug)]
 pub struct UnixStream(pub tokio::net::UnixStream);
 
@@ -98,3 +194,49 @@ impl AsyncWrite for UnixStream {
         Pin::new(&mut self.0).poll_shutdown(cx)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert_matches::assert_matches;
+    use nix::unistd::getgid;
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn test_resolve_group() {
+        assert_eq!(Gid::from_raw(1234), resolve_group("1234").unwrap());
+        assert_eq!(Gid::from_raw(0), resolve_group("root").unwrap());
+        assert_matches!(
+            resolve_group("no-such-group-for-grr-plugin"),
+            Err(Error::UnixSocketGroup(_))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_temp_socket_in_dir() {
+        let dir = tempdir().unwrap();
+        let config = SocketConfig::default()
+            .with_dir(dir.path().to_path_buf())
+            .with_group(&getgid().to_string())
+            .unwrap();
+        let temp_socket = TempSocket::new(&config).unwrap();
+        let path = temp_socket.socket_filename().unwrap();
+        let _incoming = incoming_from_path(&path, &config).await.unwrap();
+
+        let path = Path::new(&path);
+        assert!(path.starts_with(dir.path()));
+        let socket_dir = path.parent().unwrap();
+        assert!(socket_dir
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with("plugin"));
+        let socket_dir = socket_dir.metadata().unwrap();
+        assert_eq!(SOCKET_DIR_GROUP_MODE, socket_dir.mode() & 0o777);
+        assert_eq!(getgid().as_raw(), socket_dir.gid());
+        let socket = path.metadata().unwrap();
+        assert_eq!(SOCKET_GROUP_MODE, socket.mode() & 0o777);
+        assert_eq!(getgid().as_raw(), socket.gid());
+    }
+}